@@ -0,0 +1,138 @@
+use chrono::Utc;
+use clap::ValueEnum;
+use log::kv::{Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Plain text, with the instance in the message
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Native journald entries, with the instance fields as journal fields
+    Journald,
+}
+
+// The structured fields of a record, e.g. the `role` and `instance_idx` of an instance
+#[derive(Default)]
+struct Fields(Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => JsonValue::from(number),
+            None => JsonValue::from(value.to_string()),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl Fields {
+    fn from_record(record: &log::Record) -> Self {
+        let mut fields = Fields::default();
+        let _ = record.key_values().visit(&mut fields);
+        fields
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.get(key).map(|value| match value {
+            JsonValue::String(value) => value.to_string(),
+            value => value.to_string(),
+        })
+    }
+
+    // Matches the `[role idx]` prefix we used to put in the messages
+    fn prefix(&self) -> String {
+        match (self.get("role"), self.get("instance_idx")) {
+            (Some(role), Some(idx)) => format!("[{} {}] ", role, idx),
+            _ => String::new(),
+        }
+    }
+}
+
+pub fn setup_logger(log_level: log::LevelFilter, format: LogFormat) -> Result<(), fern::InitError> {
+    let dispatch = fern::Dispatch::new().level(log_level);
+
+    let dispatch = match format {
+        LogFormat::Text => dispatch
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "[{} {} {}] {}{}",
+                    Utc::now().to_rfc3339(),
+                    record.level(),
+                    record.target(),
+                    Fields::from_record(record).prefix(),
+                    message
+                ))
+            })
+            .chain(std::io::stdout()),
+        LogFormat::Json => dispatch
+            .format(|out, message, record| {
+                let mut object = Map::new();
+                object.insert("timestamp".into(), Utc::now().to_rfc3339().into());
+                object.insert("level".into(), record.level().to_string().into());
+                object.insert("target".into(), record.target().into());
+                object.insert("message".into(), message.to_string().into());
+                object.extend(Fields::from_record(record).0);
+                out.finish(format_args!("{}", JsonValue::Object(object)))
+            })
+            .chain(std::io::stdout()),
+        LogFormat::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNALD_SOCKET)?;
+            dispatch.chain(fern::Output::call(move |record| {
+                let _ = socket.send(&journald_entry(record));
+            }))
+        }
+    };
+
+    dispatch.apply()?;
+    Ok(())
+}
+
+// Journald's native protocol, fields are uppercase and values with
+// newlines are prefixed with their length.
+fn journald_entry(record: &log::Record) -> Vec<u8> {
+    let priority = match record.level() {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    };
+
+    let mut fields = vec![
+        ("MESSAGE".to_string(), record.args().to_string()),
+        ("PRIORITY".to_string(), priority.to_string()),
+        (
+            "SYSLOG_IDENTIFIER".to_string(),
+            "actions-runner".to_string(),
+        ),
+        ("TARGET".to_string(), record.target().to_string()),
+    ];
+    for (key, value) in Fields::from_record(record).0 {
+        let value = match value {
+            JsonValue::String(value) => value,
+            value => value.to_string(),
+        };
+        fields.push((key.to_uppercase(), value));
+    }
+
+    let mut entry = Vec::new();
+    for (key, value) in fields {
+        if value.contains('\n') {
+            let _ = writeln!(entry, "{}", key);
+            entry.extend((value.len() as u64).to_le_bytes());
+            entry.extend(value.as_bytes());
+            entry.push(b'\n');
+        } else {
+            let _ = writeln!(entry, "{}={}", key, value);
+        }
+    }
+    entry
+}
@@ -1,13 +1,18 @@
 use anyhow::Result;
-use builder::Builder;
+use builder::{
+    Backend, BuildMethod, BuildOptions, BuildSpec, Builder, Headroom, ImageSize, ImageSource,
+};
 use camino::Utf8PathBuf;
-use chrono::Utc;
 use clap::{Parser, Subcommand};
 use config::manager::ManagerConfig;
+use logger::{setup_logger, LogFormat};
 
 use manager::Manager;
 use std::env;
 use std::process::ExitCode;
+use store::{kernel, Reference, Store, Stored};
+
+mod logger;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,22 +27,209 @@ enum Commands {
     /// Runs the manager, which will start the instances and manage them
     Run(ManageArgs),
 
-    /// Build new image from a Dockerfile
+    /// Build new image from a Dockerfile, a build spec, an image or an OCI archive
     Build(BuildArgs),
+
+    /// Print the Dockerfile a build spec is rendered into
+    RenderSpec(RenderSpecArgs),
+
+    /// Build a read-only seed cache for a role, from an instance's cache or a directory
+    SeedCache(SeedCacheArgs),
+
+    /// Check a built image for everything the runner needs
+    VerifyImage(VerifyImageArgs),
+
+    /// Manage the images in the local image store
+    Images(ImagesArgs),
+
+    /// Manage the guest kernels in the local store
+    Kernels(KernelsArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum ImagesCommands {
+    /// List the images in the store and their tags
+    List,
+
+    /// Tag an image file, which is added to the store, or an image in the store
+    Tag { source: String, target: Reference },
+
+    /// Remove a tag, the image is removed by the next `gc` if nothing else uses it
+    Rm { reference: Reference },
+
+    /// Remove images that aren't tagged or used by a running manager
+    Gc,
+}
+
+#[derive(Subcommand, Debug)]
+enum KernelsCommands {
+    /// List the kernels in the store and their versions
+    List,
+
+    /// Add a kernel from a file or URL, roles can use it as `vmlinux:<VERSION>`
+    Import {
+        source: String,
+
+        #[arg(value_name = "VERSION")]
+        kernel_version: String,
+
+        /// Only add the kernel if it has this checksum, required for URLs
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+
+    /// Remove the tag of a version, the kernel is removed by the next `gc` if nothing else uses it
+    Rm {
+        #[arg(value_name = "VERSION")]
+        kernel_version: String,
+    },
+
+    /// Remove kernels that aren't tagged or used by a running manager
+    Gc,
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(allow_missing_positional = true)]
+#[command(group(clap::ArgGroup::new("source").required(true).args(["dockerfile", "spec", "image", "oci"])))]
 struct BuildArgs {
-    dockerfile: Utf8PathBuf,
+    dockerfile: Option<Utf8PathBuf>,
 
     output: Utf8PathBuf,
 
+    /// Build from a TOML build spec, its directory is the build context by default
+    #[arg(long)]
+    spec: Option<Utf8PathBuf>,
+
+    /// Build from an existing image, e.g. `registry/foo:tag`
+    #[arg(long)]
+    image: Option<String>,
+
+    /// Build from an OCI image layout directory, or an OCI or Docker image tarball
+    #[arg(long)]
+    oci: Option<Utf8PathBuf>,
+
+    /// `docker` or `podman`
+    #[arg(long, default_value_t)]
+    backend: Backend,
+
+    /// `KEY=VALUE` to pass to the Dockerfile, can be given more than once
+    #[arg(long = "build-arg")]
+    build_args: Vec<String>,
+
+    /// The stage of the Dockerfile to build
+    #[arg(long)]
+    target: Option<String>,
+
+    /// The build context, the current directory by default
+    #[arg(long)]
+    context: Option<Utf8PathBuf>,
+
+    /// The platform to build or pull, e.g. `linux/arm64`
+    #[arg(long)]
+    platform: Option<String>,
+
+    #[arg(long)]
+    no_cache: bool,
+
+    /// `auto`, or a size like `512M` or `20G`, a plain number is in GB
+    #[arg(short, long, default_value_t)]
+    size: ImageSize,
+
+    /// Extra space for an `auto` sized image, a percentage like `20%` or a size like `2G`
+    #[arg(long, default_value_t)]
+    headroom: Headroom,
+
+    /// `populate` (no root needed) or `mount`, detected from the version of mkfs.ext4 by default
+    #[arg(long)]
+    method: Option<BuildMethod>,
+
     #[arg(short, long)]
-    size: Option<u8>,
+    log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("source").required(true).args(["from_cache", "from_dir"])))]
+struct SeedCacheArgs {
+    output: Utf8PathBuf,
+
+    /// The cache image of a "golden" instance, which isn't running
+    #[arg(long)]
+    from_cache: Option<Utf8PathBuf>,
+
+    /// A directory with a subdirectory per cache root, e.g. `docker`
+    #[arg(long)]
+    from_dir: Option<Utf8PathBuf>,
+
+    /// `auto`, or a size like `512M` or `20G`, a plain number is in GB
+    #[arg(short, long, default_value_t)]
+    size: ImageSize,
+
+    /// Extra space for an `auto` sized image, a percentage like `20%` or a size like `2G`
+    #[arg(long, default_value_t)]
+    headroom: Headroom,
+
+    #[arg(short, long)]
+    log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct RenderSpecArgs {
+    spec: Utf8PathBuf,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct VerifyImageArgs {
+    image: Utf8PathBuf,
 
     #[arg(short, long)]
     log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct ImagesArgs {
+    #[command(subcommand)]
+    command: ImagesCommands,
+
+    /// The store, this should match `store_path` in the manager's config
+    #[arg(long, global = true, default_value = store::DEFAULT_STORE_PATH)]
+    store: Utf8PathBuf,
+
+    #[arg(short, long, global = true)]
+    log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, global = true, default_value_t)]
+    log_format: LogFormat,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct KernelsArgs {
+    #[command(subcommand)]
+    command: KernelsCommands,
+
+    /// The store, this should match `store_path` in the manager's config
+    #[arg(long, global = true, default_value = store::DEFAULT_STORE_PATH)]
+    store: Utf8PathBuf,
+
+    #[arg(short, long, global = true)]
+    log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, global = true, default_value_t)]
+    log_format: LogFormat,
 }
 
 #[derive(Parser, Debug)]
@@ -54,13 +246,17 @@ struct ManageArgs {
 
     #[arg(short, long)]
     log_level: Option<log::LevelFilter>,
+
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
 }
 
 fn main() -> Result<ExitCode> {
     match env::args().next() {
-        Some(path) if path.ends_with("actions-init") => {
-            init(&path)?;
-        }
+        Some(path) if path.ends_with("actions-init") => match env::args().nth(1).as_deref() {
+            Some("restore") => restore(&path)?,
+            _ => init(&path)?,
+        },
         Some(path) if path.ends_with("actions-run") => {
             run()?;
         }
@@ -69,6 +265,11 @@ fn main() -> Result<ExitCode> {
             match args.command {
                 Commands::Build(args) => build(args)?,
                 Commands::Run(args) => manage(args)?,
+                Commands::SeedCache(args) => seed_cache(args)?,
+                Commands::RenderSpec(args) => render_spec(args)?,
+                Commands::VerifyImage(args) => return verify_image(args),
+                Commands::Images(args) => images(args)?,
+                Commands::Kernels(args) => kernels(args)?,
             }
         }
     }
@@ -77,7 +278,7 @@ fn main() -> Result<ExitCode> {
 }
 
 fn run() -> Result<()> {
-    setup_logger(log::LevelFilter::Debug).expect("Could not setup logger");
+    setup_logger(log::LevelFilter::Debug, LogFormat::Text).expect("Could not setup logger");
 
     let runner = runner::Runner::new();
     runner.run()?;
@@ -86,7 +287,7 @@ fn run() -> Result<()> {
 }
 
 fn init(path: &str) -> Result<()> {
-    setup_logger(log::LevelFilter::Debug).expect("Could not setup logger");
+    setup_logger(log::LevelFilter::Debug, LogFormat::Text).expect("Could not setup logger");
 
     let initialiser = initialiser::Initialiser::new(path);
     initialiser.run()?;
@@ -94,20 +295,183 @@ fn init(path: &str) -> Result<()> {
     Ok(())
 }
 
+fn restore(path: &str) -> Result<()> {
+    setup_logger(log::LevelFilter::Debug, LogFormat::Text).expect("Could not setup logger");
+
+    let initialiser = initialiser::Initialiser::new(path);
+    initialiser.restore()?;
+
+    Ok(())
+}
+
 fn build(args: BuildArgs) -> Result<()> {
-    setup_logger(args.log_level.unwrap_or(log::LevelFilter::Info)).expect("Could not setup logger");
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
 
-    let builder = Builder::new(&args.dockerfile, &args.output, args.size)?;
+    let source = match (args.dockerfile, args.spec, args.image, args.oci) {
+        (Some(dockerfile), _, _, _) => ImageSource::Dockerfile(dockerfile),
+        (None, Some(spec), _, _) => ImageSource::Spec(spec),
+        (None, None, Some(image), _) => ImageSource::Image(image),
+        (None, None, None, Some(oci)) => ImageSource::Archive(oci),
+        (None, None, None, None) => unreachable!("Clap requires a source"),
+    };
+
+    let builder = Builder::new(
+        source,
+        &args.output,
+        args.size,
+        args.headroom,
+        args.method,
+        args.backend,
+        BuildOptions {
+            build_args: args.build_args,
+            target: args.target,
+            context: args.context,
+            platform: args.platform,
+            no_cache: args.no_cache,
+        },
+    )?;
     builder.build()?;
 
     Ok(())
 }
 
+fn seed_cache(args: SeedCacheArgs) -> Result<()> {
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
+
+    match (args.from_cache, args.from_dir) {
+        (Some(cache_image), _) => builder::seed::from_cache(&cache_image, &args.output)?,
+        (None, Some(source_dir)) => {
+            builder::seed::from_dir(&source_dir, &args.output, args.size, args.headroom)?
+        }
+        (None, None) => unreachable!("Clap requires a source"),
+    }
+
+    Ok(())
+}
+
+fn render_spec(args: RenderSpecArgs) -> Result<()> {
+    let spec = BuildSpec::from_file(&args.spec)?;
+    print!("{}", spec.render());
+
+    Ok(())
+}
+
+fn verify_image(args: VerifyImageArgs) -> Result<ExitCode> {
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
+
+    let own_path: Utf8PathBuf = env::current_exe()?.try_into()?;
+    let problems = builder::verify::verify(&args.image, &own_path)?;
+    if problems.is_empty() {
+        log::info!("Image: '{}' meets all runner prerequisites", args.image);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    log::error!(
+        "Image: '{}' is missing {} runner prerequisite(s)",
+        args.image,
+        problems.len()
+    );
+    Ok(ExitCode::FAILURE)
+}
+
+fn images(args: ImagesArgs) -> Result<()> {
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
+
+    let store = Store::images(&args.store);
+    match args.command {
+        ImagesCommands::List => print_stored(store.list()?),
+        ImagesCommands::Tag { source, target } => {
+            store.tag(&source, &target)?;
+        }
+        ImagesCommands::Rm { reference } => store.remove(&reference)?,
+        ImagesCommands::Gc => {
+            let removed = store.gc()?;
+            log::info!("Removed {} image(s)", removed.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn kernels(args: KernelsArgs) -> Result<()> {
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
+
+    let store = Store::kernels(&args.store);
+    match args.command {
+        KernelsCommands::List => print_stored(store.list()?),
+        KernelsCommands::Import {
+            source,
+            kernel_version,
+            sha256,
+        } => {
+            let reference = kernel::reference(&kernel_version).map_err(anyhow::Error::msg)?;
+            kernel::import(&store, &source, sha256.as_deref(), &reference)?;
+        }
+        KernelsCommands::Rm { kernel_version } => {
+            let reference = kernel::reference(&kernel_version).map_err(anyhow::Error::msg)?;
+            store.remove(&reference)?
+        }
+        KernelsCommands::Gc => {
+            let removed = store.gc()?;
+            log::info!("Removed {} kernel(s)", removed.len());
+        }
+    }
+
+    Ok(())
+}
+
+// One line per image or kernel: the short id, its tags, size and when it was built
+fn print_stored(stored: Vec<Stored>) {
+    for stored in stored {
+        let tags: Vec<String> = stored.tags.iter().map(Reference::to_string).collect();
+        println!(
+            "{}\t{}\t{}MB\t{}",
+            &stored.id[..12.min(stored.id.len())],
+            if tags.is_empty() {
+                "<none>".to_string()
+            } else {
+                tags.join(",")
+            },
+            stored.size_bytes / 1024 / 1024,
+            stored
+                .manifest
+                .map(|manifest| manifest.built_at)
+                .unwrap_or_default()
+        );
+    }
+}
+
 fn manage(args: ManageArgs) -> Result<()> {
-    setup_logger(args.log_level.unwrap_or(log::LevelFilter::Info)).expect("Could not setup logger");
+    setup_logger(
+        args.log_level.unwrap_or(log::LevelFilter::Info),
+        args.log_format,
+    )
+    .expect("Could not setup logger");
 
-    let config =
-        ManagerConfig::from_file(&args.config.clone()).expect("Could not load config");
+    let config = ManagerConfig::from_file(&args.config.clone()).expect("Could not load config");
     let mut manager = Manager::new(config);
 
     match args.debug_role {
@@ -131,20 +495,3 @@ fn manage(args: ManageArgs) -> Result<()> {
 
     Ok(())
 }
-
-fn setup_logger(log_level: log::LevelFilter) -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{} {} {}] {}",
-                Utc::now().to_rfc3339(),
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .level(log_level)
-        .chain(std::io::stdout())
-        .apply()?;
-    Ok(())
-}
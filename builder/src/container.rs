@@ -0,0 +1,318 @@
+use crate::{docker::Docker, fakeroot::Fakeroot, podman::Podman, spec::BuildSpec, BuildError};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::*;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use util::{exec, exec_spawn};
+
+// Where the root filesystem of the image comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageSource {
+    Dockerfile(Utf8PathBuf),
+    // A reference to pull, e.g. `registry/foo:tag`
+    Image(String),
+    // A build spec, rendered into a Dockerfile. Its directory is the build context by default
+    Spec(Utf8PathBuf),
+    // An OCI image layout directory, or an OCI or Docker image tarball
+    Archive(Utf8PathBuf),
+}
+
+// A spec is rendered into the work dir of the build
+const RENDERED_DOCKERFILE: &str = "Dockerfile";
+
+impl ImageSource {
+    // The Dockerfile the image is built from, if it's built at all
+    pub fn dockerfile_path(&self, work_path: &Utf8Path) -> Option<Utf8PathBuf> {
+        match self {
+            ImageSource::Dockerfile(path) => Some(path.clone()),
+            ImageSource::Spec(_) => Some(work_path.join(RENDERED_DOCKERFILE)),
+            ImageSource::Image(_) | ImageSource::Archive(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ImageSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageSource::Dockerfile(path) => write!(f, "Dockerfile '{}'", path),
+            ImageSource::Image(reference) => write!(f, "image '{}'", reference),
+            ImageSource::Archive(path) => write!(f, "archive '{}'", path),
+            ImageSource::Spec(path) => write!(f, "spec '{}'", path),
+        }
+    }
+}
+
+// Options for building an image from a Dockerfile
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildOptions {
+    // `KEY=VALUE`, or just `KEY` to take the value from the environment
+    pub build_args: Vec<String>,
+    pub target: Option<String>,
+    // The build context, the current directory by default
+    pub context: Option<Utf8PathBuf>,
+    // Also used when pulling an image, e.g. `linux/arm64`
+    pub platform: Option<String>,
+    pub no_cache: bool,
+}
+
+impl BuildOptions {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for build_arg in &self.build_args {
+            args.push("--build-arg".to_string());
+            args.push(build_arg.clone());
+        }
+        if let Some(ref target) = self.target {
+            args.push("--target".to_string());
+            args.push(target.clone());
+        }
+        args.extend(self.platform_args());
+        if self.no_cache {
+            args.push("--no-cache".to_string());
+        }
+        args
+    }
+
+    pub(crate) fn platform_args(&self) -> Vec<String> {
+        match self.platform {
+            Some(ref platform) => vec!["--platform".to_string(), platform.clone()],
+            None => Vec::new(),
+        }
+    }
+}
+
+// The container engines we can build with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl Backend {
+    pub fn container_backend(&self) -> Box<dyn ContainerBackend> {
+        match self {
+            Backend::Docker => Box::new(Docker),
+            Backend::Podman => Box::new(Podman),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Docker => write!(f, "docker"),
+            Backend::Podman => write!(f, "podman"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Backend::Docker),
+            "podman" => Ok(Backend::Podman),
+            _ => Err(format!("Unknown container backend: {}", s)),
+        }
+    }
+}
+
+// Builds or fetches images and exports their filesystem. Docker and Podman share most
+// of their command line, so the backends only differ where they have to.
+pub trait ContainerBackend {
+    fn command(&self) -> Command;
+
+    // Pull the image, and return the reference we can use for it
+    fn pull_image(&self, reference: &str, options: &BuildOptions) -> Result<String, BuildError>;
+
+    // Load an OCI image layout directory, or an image tarball
+    fn load_image(&self, path: &Utf8Path) -> Result<String, BuildError>;
+
+    // Get the image for the source, and return its ID or reference. A spec is rendered
+    // into the work dir of the build.
+    fn image(
+        &self,
+        source: &ImageSource,
+        options: &BuildOptions,
+        work_path: &Utf8Path,
+    ) -> Result<String, BuildError> {
+        match source {
+            ImageSource::Dockerfile(path) => self.build_image(path, options),
+            ImageSource::Image(reference) => self.pull_image(reference, options),
+            ImageSource::Archive(path) => self.load_image(path),
+            ImageSource::Spec(path) => {
+                let dockerfile_path = work_path.join(RENDERED_DOCKERFILE);
+                debug!("Rendering spec: '{}' to: '{}'", path, dockerfile_path);
+                std::fs::write(&dockerfile_path, BuildSpec::from_file(path)?.render())?;
+
+                let options = BuildOptions {
+                    context: options
+                        .context
+                        .clone()
+                        .or_else(|| path.parent().map(Utf8Path::to_path_buf)),
+                    ..options.clone()
+                };
+                self.build_image(&dockerfile_path, &options)
+            }
+        }
+    }
+
+    fn build_image(
+        &self,
+        source_path: &Utf8Path,
+        options: &BuildOptions,
+    ) -> Result<String, BuildError> {
+        let context = options.context.as_deref().unwrap_or(Utf8Path::new("."));
+        let output = exec(
+            self.command()
+                .args(["build", "-q", "--file", source_path.as_str()])
+                .args(options.args())
+                .arg(context.as_str()),
+        )?;
+
+        Ok(trimmed(&output.stdout))
+    }
+
+    // The container is only created and never started, so we can export images for other platforms
+    fn create_container(&self, image_id: &str) -> Result<String, BuildError> {
+        let output = exec(self.command().args(["create", image_id]))?;
+
+        // Get the container id from the output, ignoring whitespace and newlines
+        Ok(trimmed(&output.stdout))
+    }
+
+    fn remove_container(&self, container_id: &str) -> Result<(), BuildError> {
+        exec(self.command().args(["rm", "-f", container_id]))?;
+        Ok(())
+    }
+
+    // The ID of an image we have a reference to
+    fn image_id(&self, image: &str) -> Result<String, BuildError> {
+        let output = exec(
+            self.command()
+                .args(["image", "inspect", "--format", "{{.Id}}", image]),
+        )?;
+
+        Ok(trimmed(&output.stdout))
+    }
+
+    // The size of the image's filesystem in bytes
+    fn image_size(&self, image_id: &str) -> Result<u64, BuildError> {
+        let output =
+            exec(
+                self.command()
+                    .args(["image", "inspect", "--format", "{{.Size}}", image_id]),
+            )?;
+
+        let size = String::from_utf8(output.stdout)?;
+        size.trim()
+            .parse()
+            .map_err(|_| BuildError::Container(format!("Invalid image size: {}", size.trim())))
+    }
+
+    // Extract the container's filesystem into the path, under `fakeroot` when we're populating
+    fn export_container(
+        &self,
+        container_id: &str,
+        path: &Utf8Path,
+        fakeroot: Option<&Fakeroot>,
+    ) -> Result<(), BuildError> {
+        let output = exec_spawn(
+            self.command()
+                .args(["cp", &format!("{}:/", container_id), "-"])
+                .stdout(Stdio::piped()),
+        )?;
+
+        let mut tar = Command::new("tar");
+        tar.args(["xf", "-", "-C", path.as_str()]);
+        if let Some(fakeroot) = fakeroot {
+            tar = fakeroot.wrap(&tar);
+        }
+        let _ = exec(tar.stdin(output.stdout.unwrap()))?;
+
+        Ok(())
+    }
+}
+
+// Command output without any whitespace or newlines
+pub(crate) fn trimmed(output: &[u8]) -> String {
+    let trimmed_line = output
+        .iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .copied()
+        .collect::<Vec<u8>>();
+
+    String::from_utf8_lossy(&trimmed_line).to_string()
+}
+
+// Find the image in the output of `load`, e.g. `Loaded image: foo:latest` or
+// `Loaded image ID: sha256:1234`
+pub(crate) fn parse_loaded_image(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Loaded image"))
+        .filter_map(|line| line.split_once(": "))
+        .filter_map(|(_, images)| images.split(',').next())
+        .map(|image| image.trim().to_string())
+        .find(|image| !image.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_options_args() {
+        let options = BuildOptions {
+            build_args: vec!["CHROME_DRIVER_VERSION=114".to_string(), "TOKEN".to_string()],
+            target: Some("runner".to_string()),
+            context: Some("./images".into()),
+            platform: Some("linux/arm64".to_string()),
+            no_cache: true,
+        };
+
+        assert_eq!(
+            options.args().join(" "),
+            "--build-arg CHROME_DRIVER_VERSION=114 --build-arg TOKEN --target runner \
+             --platform linux/arm64 --no-cache"
+        );
+        assert!(BuildOptions::default().args().is_empty());
+    }
+
+    #[test]
+    fn test_dockerfile_path() {
+        let work_path = Utf8Path::new("/tmp/actions-runner/build");
+        assert_eq!(
+            ImageSource::Dockerfile("images/Dockerfile".into()).dockerfile_path(work_path),
+            Some("images/Dockerfile".into())
+        );
+        assert_eq!(
+            ImageSource::Spec("images/spec.toml".into()).dockerfile_path(work_path),
+            Some(work_path.join("Dockerfile"))
+        );
+        assert_eq!(
+            ImageSource::Image("registry/foo:tag".to_string()).dockerfile_path(work_path),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_loaded_image() {
+        assert_eq!(
+            parse_loaded_image("Loaded image: registry/foo:tag\n"),
+            Some("registry/foo:tag".to_string())
+        );
+        assert_eq!(
+            parse_loaded_image("Loaded image ID: sha256:1234\n"),
+            Some("sha256:1234".to_string())
+        );
+        assert_eq!(
+            parse_loaded_image("Getting image source signatures\nLoaded image(s): localhost/foo:latest,localhost/bar:latest\n"),
+            Some("localhost/foo:latest".to_string())
+        );
+        assert_eq!(parse_loaded_image("Error: no image"), None);
+    }
+}
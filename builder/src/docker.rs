@@ -1,47 +1,42 @@
+use crate::container::{parse_loaded_image, BuildOptions, ContainerBackend};
 use crate::BuildError;
-use anyhow::Result;
-use camino::Utf8PathBuf;
-use std::process::Command;
+use camino::Utf8Path;
+use std::process::{Command, Stdio};
 use util::{exec, exec_spawn};
 
-pub fn build_image(source_path: &Utf8PathBuf) -> Result<String, BuildError> {
-    let output =
-        exec(Command::new("docker").args(["build", "-q", "--file", source_path.as_str(), "."]))?;
+pub struct Docker;
 
-    let trimmed_line = output
-        .stdout
-        .into_iter()
-        .filter(|c| !c.is_ascii_whitespace())
-        .collect::<Vec<u8>>();
-
-    Ok(String::from_utf8_lossy(&trimmed_line).to_string())
-}
-
-pub fn create_container(image_id: &str) -> Result<String, BuildError> {
-    let output = exec(Command::new("docker").args(["run", "-td", image_id]))?;
-
-    // Get the container id from the output, ignoring whitespace and newlines
-    let trimmed_line = output
-        .stdout
-        .into_iter()
-        .filter(|c| !c.is_ascii_whitespace())
-        .collect::<Vec<u8>>();
-
-    Ok(String::from_utf8_lossy(&trimmed_line).to_string())
-}
-
-pub fn export_container(container_id: &str, mount_path: &Utf8PathBuf) -> Result<(), BuildError> {
-    let output = exec_spawn(
+impl ContainerBackend for Docker {
+    fn command(&self) -> Command {
         Command::new("docker")
-            .args(["cp", &format!("{}:/", container_id), "-"])
-            .stdout(std::process::Stdio::piped()),
-    )?;
-
-    let _ = exec(
-        Command::new("tar")
-            .args(["xf", "-", "-C", mount_path.as_str()])
-            .stdin(output.stdout.unwrap()),
-    )?;
-
-    Ok(())
+    }
+
+    // `docker pull -q` prints the reference, which works everywhere an image ID does
+    fn pull_image(&self, reference: &str, options: &BuildOptions) -> Result<String, BuildError> {
+        exec(
+            self.command()
+                .args(["pull", "-q"])
+                .args(options.platform_args())
+                .arg(reference),
+        )?;
+        Ok(reference.to_string())
+    }
+
+    // Docker only loads tarballs, so an OCI layout directory is streamed in as one
+    fn load_image(&self, path: &Utf8Path) -> Result<String, BuildError> {
+        let output = if path.is_dir() {
+            let tar = exec_spawn(
+                Command::new("tar")
+                    .args(["cf", "-", "-C", path.as_str(), "."])
+                    .stdout(Stdio::piped()),
+            )?;
+            exec(self.command().arg("load").stdin(tar.stdout.unwrap()))?
+        } else {
+            exec(self.command().args(["load", "-i", path.as_str()]))?
+        };
+
+        let stdout = String::from_utf8(output.stdout)?;
+        parse_loaded_image(&stdout)
+            .ok_or_else(|| BuildError::Container(format!("No image loaded from: {}", path)))
+    }
 }
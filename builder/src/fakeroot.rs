@@ -0,0 +1,59 @@
+use crate::BuildError;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::process::Command;
+use util::exec;
+
+const STATE_FILE: &str = "fakeroot.state";
+
+// Runs commands under `fakeroot`, which records the ownership, modes and device nodes they
+// set in a state file instead of on disk. The export keeps those when we're not root, and
+// `mkfs.ext4 -d` writes them into the image.
+pub struct Fakeroot {
+    state_path: Utf8PathBuf,
+}
+
+impl Fakeroot {
+    pub fn new(work_path: &Utf8Path) -> Result<Self, BuildError> {
+        let state_path = work_path.join(STATE_FILE);
+        std::fs::write(&state_path, "")?;
+        Ok(Self { state_path })
+    }
+
+    pub fn check() -> Result<(), BuildError> {
+        exec(Command::new("fakeroot").arg("-v"))?;
+        Ok(())
+    }
+
+    // The same command, loading the state of the commands before it and saving it again.
+    // Files fakeroot doesn't know about appear to be owned by root.
+    pub fn wrap(&self, command: &Command) -> Command {
+        let mut wrapped = Command::new("fakeroot");
+        wrapped
+            .args(["-i", self.state_path.as_str()])
+            .args(["-s", self.state_path.as_str()])
+            .arg("--")
+            .arg(command.get_program())
+            .args(command.get_args());
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let fakeroot = Fakeroot {
+            state_path: "/tmp/build/fakeroot.state".into(),
+        };
+        let mut command = Command::new("tar");
+        command.args(["xf", "-", "-C", "/tmp/build/rootfs"]);
+
+        assert_eq!(
+            util::inner::to_string(&fakeroot.wrap(&command)),
+            "fakeroot -i /tmp/build/fakeroot.state -s /tmp/build/fakeroot.state -- \
+             tar xf - -C /tmp/build/rootfs"
+        );
+    }
+}
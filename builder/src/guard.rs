@@ -0,0 +1,220 @@
+use crate::{BuildError, ContainerBackend};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::*;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+use util::mount;
+
+// Set by SIGINT and SIGTERM, which would otherwise kill us without dropping the guards
+static INTERRUPTED: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+static HANDLE_SIGNALS: Once = Once::new();
+
+// Turn SIGINT and SIGTERM into an error at the next `check_interrupted`, so the guards clean
+// up on the way out. A second signal still terminates right away.
+pub fn handle_signals() -> Result<(), BuildError> {
+    let mut result = Ok(());
+    HANDLE_SIGNALS.call_once(|| {
+        for signal in [SIGINT, SIGTERM] {
+            result = flag::register_conditional_shutdown(signal, 1, Arc::clone(&INTERRUPTED))
+                .and_then(|_| flag::register(signal, Arc::clone(&INTERRUPTED)))
+                .map(|_| ());
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(result?)
+}
+
+pub fn check_interrupted() -> Result<(), BuildError> {
+    check_flag(&INTERRUPTED)
+}
+
+fn check_flag(interrupted: &AtomicBool) -> Result<(), BuildError> {
+    match interrupted.load(Ordering::SeqCst) {
+        true => Err(BuildError::Interrupted),
+        false => Ok(()),
+    }
+}
+
+// A work directory of its own for every build, removed when it goes out of scope
+pub struct WorkDir {
+    path: Utf8PathBuf,
+}
+
+impl WorkDir {
+    pub fn new(base_path: impl AsRef<Utf8Path>, prefix: &str) -> Result<Self, BuildError> {
+        let base_path = base_path.as_ref();
+        std::fs::create_dir_all(base_path)?;
+
+        // Creating the directory fails if another build got there first, so try the next name
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or_default();
+        let mut attempt = 0;
+        loop {
+            let path = base_path.join(format!(
+                "{}-{}-{}-{}",
+                prefix,
+                std::process::id(),
+                nanos,
+                attempt
+            ));
+            match std::fs::create_dir(&path) {
+                Ok(_) => {
+                    debug!("Created work dir: '{}'", path);
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        // Never remove what's inside an image we couldn't unmount
+        match has_mounts(&self.path) {
+            Ok(false) => {}
+            Ok(true) => {
+                warn!("Not removing work dir '{}', it has mounts", self.path);
+                return;
+            }
+            Err(e) => {
+                warn!("Could not check mounts of work dir '{}': {}", self.path, e);
+                return;
+            }
+        }
+
+        debug!("Removing work dir: '{}'", self.path);
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Could not remove work dir '{}': {}", self.path, e);
+        }
+    }
+}
+
+// Whether anything is mounted on or below the path
+fn has_mounts(path: &Utf8Path) -> std::io::Result<bool> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|mount_point| Utf8Path::new(mount_point).starts_with(path)))
+}
+
+// A mounted image, unmounted when it goes out of scope. Unmounting detaches the loop
+// device `mount` set up for the image as well.
+pub struct Mount {
+    path: Utf8PathBuf,
+    mounted: bool,
+}
+
+impl Mount {
+    pub fn image(
+        image_path: impl AsRef<Utf8Path>,
+        path: impl AsRef<Utf8Path>,
+    ) -> Result<Self, BuildError> {
+        let path = path.as_ref().to_path_buf();
+        mount::mount_image(image_path, &path)?;
+        Ok(Self {
+            path,
+            mounted: true,
+        })
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    // Unmount now, so we see if it failed
+    pub fn unmount(mut self) -> Result<(), BuildError> {
+        self.mounted = false;
+        mount::unmount(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+
+        debug!("Unmounting: '{}'", self.path);
+        if let Err(e) = mount::unmount(&self.path) {
+            warn!("Could not unmount '{}': {}", self.path, e);
+        }
+    }
+}
+
+// A container we export the filesystem of, removed when it goes out of scope
+pub struct Container<'a> {
+    backend: &'a dyn ContainerBackend,
+    id: String,
+}
+
+impl<'a> Container<'a> {
+    pub fn create(backend: &'a dyn ContainerBackend, image_id: &str) -> Result<Self, BuildError> {
+        let id = backend.create_container(image_id)?;
+        Ok(Self { backend, id })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Drop for Container<'_> {
+    fn drop(&mut self) {
+        debug!("Removing container: '{}'", self.id);
+        if let Err(e) = self.backend.remove_container(&self.id) {
+            warn!("Could not remove container '{}': {}", self.id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_flag() {
+        let interrupted = AtomicBool::new(false);
+        assert!(check_flag(&interrupted).is_ok());
+
+        interrupted.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            check_flag(&interrupted),
+            Err(BuildError::Interrupted)
+        ));
+    }
+
+    #[test]
+    fn test_work_dir() {
+        let base_path = Utf8PathBuf::from("/tmp/test_work_dir");
+
+        let first = WorkDir::new(&base_path, "build").expect("Could not create work dir");
+        let second = WorkDir::new(&base_path, "build").expect("Could not create work dir");
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().is_dir());
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.path().is_dir());
+        assert!(!has_mounts(second.path()).unwrap());
+        assert!(has_mounts(Utf8Path::new("/")).unwrap());
+
+        drop(second);
+        let _ = std::fs::remove_dir_all(&base_path);
+    }
+}
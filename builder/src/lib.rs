@@ -1,15 +1,35 @@
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use config::image::ImageManifest;
 use log::*;
 use std::env;
+use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
-use util::{fs, mount};
+use util::{exec, fs};
 
+pub mod container;
 pub mod docker;
+pub mod fakeroot;
+pub mod guard;
+pub mod manifest;
+pub mod podman;
 pub mod qemu;
+pub mod seed;
+pub mod size;
+pub mod spec;
+pub mod verify;
 
-const MOUNT_PATH: &str = "/tmp/actions-runner/mnt";
-const WORK_PATH: &str = "/tmp/actions-runner";
-const DEFAULT_IMAGE_SIZE_GB: u8 = 10;
+pub use container::{Backend, BuildOptions, ContainerBackend, ImageSource};
+use fakeroot::Fakeroot;
+pub use size::{Headroom, ImageSize};
+pub use spec::BuildSpec;
+
+// Every build gets its own work dir in here, with the mount and staging dirs inside it
+pub(crate) const WORK_PATH: &str = "/tmp/actions-runner";
+const MOUNT_DIR: &str = "mnt";
+const STAGING_DIR: &str = "rootfs";
+// `mkfs.ext4 -d` was added in e2fsprogs 1.43
+const MIN_POPULATE_VERSION: (u32, u32) = (1, 43);
 
 #[derive(Error, Debug)]
 pub enum BuildError {
@@ -21,113 +41,301 @@ pub enum BuildError {
     Utf8(#[from] std::string::FromUtf8Error),
     #[error("Utf8 conversion error: {:?}", self)]
     PathBuf(#[from] camino::FromPathBufError),
-    #[error("Docker build error: {:?}", .0)]
-    DockerBuild(String),
+    #[error("Container error: {:?}", .0)]
+    Container(String),
     #[error("Qemu build error: {stderr}", stderr = "0.stderr")]
     QemuBuild(util::CommandExecutionError),
     #[error("Could not find our own binary: {:?}", .0)]
     SelfNotFound(std::io::Error),
+    #[error("Config error: {:?}", .0)]
+    Config(#[from] config::ConfigError),
+    #[error("Build spec error: {}", .0)]
+    Spec(String),
+    #[error("Image doesn't meet the runner prerequisites: {}", verify::describe(.0))]
+    Invalid(Vec<verify::Problem>),
+    #[error("Interrupted by a signal")]
+    Interrupted,
+}
+
+// How the container's filesystem ends up in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildMethod {
+    // Export to a staging directory and create the filesystem from it with `mkfs.ext4 -d`,
+    // which doesn't need loop devices or root. Both run under `fakeroot`, so the export keeps
+    // the ownership of its files (and setuid bits).
+    Populate,
+    // Loop mount the image and export the container into it, which needs root
+    Mount,
+}
+
+impl BuildMethod {
+    // Populate the image without mounting it, if we have `fakeroot` and our `mkfs.ext4` supports that
+    pub fn detect() -> Self {
+        if let Err(e) = Fakeroot::check() {
+            info!("Could not run fakeroot, falling back to mounting: {}", e);
+            return BuildMethod::Mount;
+        }
+
+        match fs::mke2fs_version() {
+            Ok(version) if version >= MIN_POPULATE_VERSION => BuildMethod::Populate,
+            Ok((major, minor)) => {
+                info!(
+                    "mkfs.ext4 {}.{} can't populate images, falling back to mounting",
+                    major, minor
+                );
+                BuildMethod::Mount
+            }
+            Err(e) => {
+                info!(
+                    "Could not detect mkfs.ext4 version, falling back to mounting: {}",
+                    e
+                );
+                BuildMethod::Mount
+            }
+        }
+    }
+}
+
+impl fmt::Display for BuildMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildMethod::Populate => write!(f, "populate"),
+            BuildMethod::Mount => write!(f, "mount"),
+        }
+    }
+}
+
+impl FromStr for BuildMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "populate" => Ok(BuildMethod::Populate),
+            "mount" => Ok(BuildMethod::Mount),
+            _ => Err(format!("Unknown build method: {}", s)),
+        }
+    }
 }
 
 pub struct Builder {
     own_path: Utf8PathBuf,
-    source_path: Utf8PathBuf,
+    source: ImageSource,
+    backend: Box<dyn ContainerBackend>,
+    build_options: BuildOptions,
     output_path: Utf8PathBuf,
-    work_path: Utf8PathBuf,
-    mount_path: Utf8PathBuf,
-    size_gb: u8,
+    size: ImageSize,
+    headroom: Headroom,
+    method: BuildMethod,
 }
 
 impl Builder {
     pub fn new(
-        source_path: &Utf8PathBuf,
+        source: ImageSource,
         output_path: &Utf8PathBuf,
-        size_gb: Option<u8>,
+        size: ImageSize,
+        headroom: Headroom,
+        method: Option<BuildMethod>,
+        backend: Backend,
+        build_options: BuildOptions,
     ) -> Result<Self, BuildError> {
         let current_dir: Utf8PathBuf = env::current_dir()?.try_into()?;
         let own_path: Utf8PathBuf = env::current_exe()?.try_into()?;
+        let source = match source {
+            ImageSource::Dockerfile(path) => ImageSource::Dockerfile(current_dir.join(path)),
+            ImageSource::Archive(path) => ImageSource::Archive(current_dir.join(path)),
+            ImageSource::Spec(path) => ImageSource::Spec(current_dir.join(path)),
+            ImageSource::Image(reference) => ImageSource::Image(reference),
+        };
+        let build_options = BuildOptions {
+            context: build_options
+                .context
+                .map(|context| current_dir.join(context)),
+            ..build_options
+        };
+        info!("Using container backend: {}", backend);
 
         Ok(Self {
             own_path,
-            source_path: [&current_dir, source_path].iter().collect(),
+            source,
+            backend: backend.container_backend(),
+            build_options,
             output_path: [&current_dir, output_path].iter().collect(),
-            size_gb: size_gb.unwrap_or(DEFAULT_IMAGE_SIZE_GB),
-            work_path: WORK_PATH.into(),
-            mount_path: MOUNT_PATH.into(),
+            size,
+            headroom,
+            method: method.unwrap_or_else(BuildMethod::detect),
         })
     }
 
-    pub fn build_inner(&self) -> Result<(), BuildError> {
-        // Build the given Dockerfile into an image, and get the image ID
-        debug!("Building image from: '{}'", self.source_path);
-        let image_id = docker::build_image(&self.source_path)?;
+    fn build_inner(&self, work_path: &Utf8Path) -> Result<(), BuildError> {
+        // Build, pull or load the image, and get the image ID
+        debug!("Getting image from: {}", self.source);
+        let image_id = self
+            .backend
+            .image(&self.source, &self.build_options, work_path)?;
+        guard::check_interrupted()?;
+
+        // Create a container from the image, it's removed again when we're done
+        let container = guard::Container::create(self.backend.as_ref(), &image_id)?;
+
+        info!("Building image with method: {}", self.method);
+        let (image_path, manifest) = match self.method {
+            BuildMethod::Populate => self.populate_image(&image_id, container.id(), work_path)?,
+            BuildMethod::Mount => self.mount_image(&image_id, container.id(), work_path)?,
+        };
+
+        // Don't publish an image the runner can't boot with
+        guard::check_interrupted()?;
+        debug!("Verifying image: '{}'", &image_path);
+        let problems = verify::verify(&image_path, &self.own_path)?;
+        if !problems.is_empty() {
+            return Err(BuildError::Invalid(problems));
+        }
+        guard::check_interrupted()?;
+
+        debug!(
+            "Copying image from: '{}' to: '{}'",
+            &image_path, &self.output_path
+        );
+        fs::clone_file(&image_path, &self.output_path, fs::CloneStrategy::Reflink)?;
 
-        // Get the container ID from the image ID
-        let container_id = docker::create_container(&image_id)?;
+        let manifest_path = ImageManifest::sidecar_path(&self.output_path);
+        debug!("Writing image manifest to: '{}'", manifest_path);
+        manifest.write(&manifest_path)?;
+        info!("Image {}", manifest);
 
+        debug!("Done!");
+        Ok(())
+    }
+
+    // Export the container to a staging directory, and create the filesystem from it
+    fn populate_image(
+        &self,
+        image_id: &str,
+        container_id: &str,
+        work_path: &Utf8Path,
+    ) -> Result<(Utf8PathBuf, ImageManifest), BuildError> {
+        let staging_path = work_path.join(STAGING_DIR);
+        debug!("Creating staging directory on: '{}'", &staging_path);
+        fs::mkdir_p(&staging_path)?;
+
+        debug!(
+            "Exporting container: '{}' to: {}",
+            container_id, staging_path
+        );
+        let fakeroot = Fakeroot::new(work_path)?;
+        self.backend
+            .export_container(container_id, &staging_path, Some(&fakeroot))?;
+        guard::check_interrupted()?;
+
+        debug!(
+            "Copy ourselves from '{}' to '{}'",
+            &self.own_path,
+            staging_path.join("sbin/actions-init")
+        );
+        fs::copy_sparse(&self.own_path, staging_path.join("sbin/actions-init"))?;
+
+        let size_mb = self
+            .size
+            .resolve(self.headroom, || fs::dir_size(&staging_path))?;
+        let manifest = manifest::write(
+            &staging_path,
+            &self.source,
+            work_path,
+            &self.backend.image_id(image_id)?,
+            size_mb,
+        )?;
+        let inodes = size::inode_count(size_mb, fs::dir_inodes(&staging_path)?);
+        info!(
+            "Creating rootfs in: '{}' with size: {}MB",
+            work_path, size_mb
+        );
+        let image_path = qemu::create_fs(work_path, size_mb)?;
+
+        debug!(
+            "Creating ext4 filesystem on: {} from: {} with {} inodes",
+            &image_path, &staging_path, inodes
+        );
+        exec(&mut fakeroot.wrap(&fs::mkfs_ext4_from_dir_command(
+            &image_path,
+            &staging_path,
+            Some(inodes),
+        )))?;
+
+        Ok((image_path, manifest))
+    }
+
+    // Mount the image, and export the container into it
+    fn mount_image(
+        &self,
+        image_id: &str,
+        container_id: &str,
+        work_path: &Utf8Path,
+    ) -> Result<(Utf8PathBuf, ImageManifest), BuildError> {
         // Create the mount directory, we use this to copy the data into an image
-        debug!("Creating directory on: '{}'", &self.mount_path);
-        fs::mkdir_p(&self.mount_path)?;
+        let mount_path = work_path.join(MOUNT_DIR);
+        debug!("Creating directory on: '{}'", &mount_path);
+        fs::mkdir_p(&mount_path)?;
+
+        // The export doesn't exist before the image does, so we go by the size of the Docker image
+        let size_mb = self.size.resolve(self.headroom, || {
+            Ok::<_, BuildError>(
+                self.backend.image_size(image_id)? + std::fs::metadata(&self.own_path)?.len(),
+            )
+        })?;
 
         // Create the rootfs image, and mount it.
-        debug!(
-            "Creating rootfs in: '{}' with size: {}GB",
-            &self.work_path, self.size_gb
+        info!(
+            "Creating rootfs in: '{}' with size: {}MB",
+            work_path, size_mb
         );
-        let image_path = qemu::create_fs(&self.work_path, self.size_gb)?;
+        let image_path = qemu::create_fs(work_path, size_mb)?;
 
         // Create a filesystem on the image
         debug!("Creating ext4 filesystem on: {}", &image_path);
         fs::mkfs_ext4(&image_path)?;
 
         // Mount the image so we can add files to it
-        debug!(
-            "Mounting root image: '{}' on: {}",
-            &image_path, self.mount_path
-        );
-        mount::mount_image(&image_path, &self.mount_path)?;
+        debug!("Mounting root image: '{}' on: {}", &image_path, mount_path);
+        let mount = guard::Mount::image(&image_path, &mount_path)?;
 
         // Copy the data from the container into the image
         debug!(
             "Exporting container: '{}' to: {}",
-            &container_id, self.mount_path
+            container_id,
+            mount.path()
         );
-        docker::export_container(&container_id, &self.mount_path)?;
+        self.backend
+            .export_container(container_id, mount.path(), None)?;
+        guard::check_interrupted()?;
 
         // Copy our own binary into the image
         debug!(
             "Copy ourselves from '{}' to '{}'",
             &self.own_path,
-            self.mount_path.join("sbin/actions-init")
+            mount.path().join("sbin/actions-init")
         );
-        fs::copy_sparse(&self.own_path, self.mount_path.join("sbin/actions-init"))?;
+        fs::copy_sparse(&self.own_path, mount.path().join("sbin/actions-init"))?;
 
-        debug!("Unmounting the image from: '{}'", &self.mount_path);
-        // Unmount the image
-        mount::unmount(&self.mount_path)?;
-
-        debug!(
-            "Copying image from: '{}' to: '{}'",
-            &image_path, &self.output_path
-        );
-        fs::copy_sparse(&image_path, &self.output_path)?;
+        let manifest = manifest::write(
+            mount.path(),
+            &self.source,
+            work_path,
+            &self.backend.image_id(image_id)?,
+            size_mb,
+        )?;
 
-        // Cleanup
-        fs::rm_rf(&self.work_path)?;
+        // Unmount the image, it's also unmounted if anything above failed
+        debug!("Unmounting the image from: '{}'", mount.path());
+        mount.unmount()?;
 
-        debug!("Done!");
-        Ok(())
+        Ok((image_path, manifest))
     }
 
-    // Runs the inner build, and cleans up after failure
+    // Runs the build in a work dir of its own. The guards unmount the image, remove the
+    // container and the work dir, whether the build succeeded, failed or was interrupted.
     pub fn build(&self) -> Result<(), BuildError> {
-        match self.build_inner() {
-            Ok(res) => Ok(res),
-            Err(e) => {
-                // let _ = mount::unmount(&self.mount_path);
-                //let _ = fs::rm_rf(&self.work_path);
-                Err(e)
-            }
-        }
+        guard::handle_signals()?;
+        let work_dir = guard::WorkDir::new(WORK_PATH, "build")?;
+        self.build_inner(work_dir.path())
     }
 }
@@ -0,0 +1,80 @@
+use crate::{BuildError, ImageSource};
+use camino::Utf8Path;
+use config::image::{ImageManifest, IMAGE_MANIFEST_PATH};
+use log::*;
+use util::fs;
+
+// The runner doesn't ship a version file, but its .NET dependency file names the version
+const RUNNER_DEPS_PATH: &str = "home/runner/bin/Runner.Listener.deps.json";
+const RUNNER_DEPS_KEY: &str = "\"Runner.Listener/";
+
+// Describe the image we're building from the root of its filesystem, and write the
+// manifest into it. For a spec we hash the Dockerfile it was rendered into in the work dir.
+pub fn write(
+    root_path: &Utf8Path,
+    source: &ImageSource,
+    work_path: &Utf8Path,
+    image_id: &str,
+    size_mb: u64,
+) -> Result<ImageManifest, BuildError> {
+    let dockerfile_sha256 = source
+        .dockerfile_path(work_path)
+        .map(fs::sha256)
+        .transpose()?;
+
+    let manifest = ImageManifest {
+        source: source.to_string(),
+        dockerfile_sha256,
+        image_id: image_id.to_string(),
+        built_at: chrono::Utc::now().to_rfc3339(),
+        builder_version: env!("CARGO_PKG_VERSION").to_string(),
+        runner_version: runner_version(root_path),
+        size_bytes: size_mb * 1024 * 1024,
+    };
+
+    let manifest_path = root_path.join(IMAGE_MANIFEST_PATH);
+    debug!("Writing image manifest to: '{}'", manifest_path);
+    if let Some(parent) = manifest_path.parent() {
+        fs::mkdir_p(parent)?;
+    }
+    manifest.write(&manifest_path)?;
+
+    Ok(manifest)
+}
+
+fn runner_version(root_path: &Utf8Path) -> Option<String> {
+    match std::fs::read_to_string(root_path.join(RUNNER_DEPS_PATH)) {
+        Ok(deps) => parse_runner_version(&deps),
+        Err(e) => {
+            warn!("Could not read the version of the runner: {}", e);
+            None
+        }
+    }
+}
+
+fn parse_runner_version(deps: &str) -> Option<String> {
+    let start = deps.find(RUNNER_DEPS_KEY)? + RUNNER_DEPS_KEY.len();
+    let end = deps[start..].find('"')?;
+    Some(deps[start..start + end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_runner_version() {
+        let deps = r#"{
+          "targets": {
+            ".NETCoreApp,Version=v6.0/linux-x64": {
+              "Runner.Listener/2.311.0": {
+                "dependencies": {}
+              }
+            }
+          }
+        }"#;
+
+        assert_eq!(parse_runner_version(deps), Some("2.311.0".to_string()));
+        assert_eq!(parse_runner_version("{}"), None);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::container::{parse_loaded_image, trimmed, BuildOptions, ContainerBackend};
+use crate::BuildError;
+use camino::Utf8Path;
+use std::process::Command;
+use util::exec;
+
+// Podman (or Buildah through Podman) doesn't need a daemon, it can also run rootless
+pub struct Podman;
+
+impl ContainerBackend for Podman {
+    fn command(&self) -> Command {
+        Command::new("podman")
+    }
+
+    // `podman pull -q` prints the ID of the image
+    fn pull_image(&self, reference: &str, options: &BuildOptions) -> Result<String, BuildError> {
+        let output = exec(
+            self.command()
+                .args(["pull", "-q"])
+                .args(options.platform_args())
+                .arg(reference),
+        )?;
+        Ok(trimmed(&output.stdout))
+    }
+
+    // Podman reads OCI layout directories directly, and loads any kind of tarball
+    fn load_image(&self, path: &Utf8Path) -> Result<String, BuildError> {
+        if path.is_dir() {
+            return self.pull_image(&format!("oci:{}", path), &BuildOptions::default());
+        }
+
+        let output = exec(self.command().args(["load", "-i", path.as_str()]))?;
+        let stdout = String::from_utf8(output.stdout)?;
+        parse_loaded_image(&stdout)
+            .ok_or_else(|| BuildError::Container(format!("No image loaded from: {}", path)))
+    }
+}
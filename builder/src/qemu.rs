@@ -1,11 +1,11 @@
 use crate::BuildError;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use std::process::Command;
 use util::exec;
 
 pub const IMAGE_NAME: &str = "image.ext4";
 
-pub fn create_fs(path: &Utf8PathBuf, size_gb: u8) -> Result<Utf8PathBuf, BuildError> {
+pub fn create_fs(path: &Utf8Path, size_mb: u64) -> Result<Utf8PathBuf, BuildError> {
     let image_path = path.join(IMAGE_NAME);
 
     exec(Command::new("qemu-img").args([
@@ -13,7 +13,7 @@ pub fn create_fs(path: &Utf8PathBuf, size_gb: u8) -> Result<Utf8PathBuf, BuildEr
         "-f",
         "raw",
         image_path.as_str(),
-        &format!("{}G", size_gb),
+        &format!("{}M", size_mb),
     ]))?;
 
     Ok(image_path)
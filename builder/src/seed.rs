@@ -0,0 +1,191 @@
+use crate::guard::{self, WorkDir};
+use crate::size::inode_count;
+use crate::{qemu, BuildError, Headroom, ImageSize, WORK_PATH};
+use camino::Utf8Path;
+use config::{CACHE_LAST_USED_DIR, CACHE_WORK_DIR};
+use log::*;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::process::Command;
+use util::{exec, fs};
+
+// Inodes below this are reserved by ext4, e.g. for the root directory and the journal
+const EXT4_FIRST_INODE: u64 = 11;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+// Build a seed cache from the cache disk of a "golden" instance. The instance can't be running.
+pub fn from_cache(cache_image: &Utf8Path, output_path: &Utf8Path) -> Result<(), BuildError> {
+    guard::handle_signals()?;
+    debug!(
+        "Copying cache from: '{}' to: '{}'",
+        cache_image, output_path
+    );
+    fs::clone_file(cache_image, output_path, fs::CloneStrategy::Reflink)?;
+
+    // The bookkeeping of the instance doesn't belong in the seed. A guest wrote the cache, so
+    // it's removed with `debugfs` instead of mounting the image, going by inode numbers only.
+    let mut requests = Vec::new();
+    for entry in list(output_path, "/")? {
+        if [CACHE_LAST_USED_DIR, CACHE_WORK_DIR].contains(&entry.name.as_str()) && entry.is_dir {
+            free_inode(
+                output_path,
+                entry.inode,
+                true,
+                &mut requests,
+                &mut HashSet::new(),
+            )?;
+            requests.push(format!("unlink /{}", entry.name));
+        }
+    }
+    if !requests.is_empty() {
+        let work_dir = WorkDir::new(WORK_PATH, "seed")?;
+        let requests_path = work_dir.path().join("debugfs");
+        std::fs::write(&requests_path, requests.join("\n"))?;
+        debug!(
+            "Removing the bookkeeping of the instance from: '{}'",
+            output_path
+        );
+        exec(Command::new("debugfs").args([
+            "-w",
+            "-f",
+            requests_path.as_str(),
+            output_path.as_str(),
+        ]))?;
+        // Fixes the link count of the root directory, and gives the freed blocks back
+        fs::e2fsck_discard(output_path)?;
+    }
+
+    debug!("Done!");
+    Ok(())
+}
+
+// An entry of a directory in an ext4 image
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    inode: u64,
+    is_dir: bool,
+    name: String,
+}
+
+// The entries of a directory, without `.` and `..`
+fn list(image: &Utf8Path, dir: &str) -> Result<Vec<Entry>, BuildError> {
+    let output =
+        exec(Command::new("debugfs").args(["-R", &format!("ls -p {}", dir), image.as_str()]))?;
+    Ok(parse_entries(&String::from_utf8(output.stdout)?))
+}
+
+// `ls -p` prints `/<inode>/<mode>/<uid>/<gid>/<name>/<size>/` per entry
+fn parse_entries(output: &str) -> Vec<Entry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields = line.strip_prefix('/')?.strip_suffix('/')?;
+            let mut parts = fields.splitn(5, '/');
+            let inode = parts.next()?.parse().ok()?;
+            let mode = u32::from_str_radix(parts.next()?, 8).ok()?;
+            let name = parts.nth(2)?.rsplit_once('/')?.0.to_string();
+            Some(Entry {
+                inode,
+                is_dir: mode & S_IFMT == S_IFDIR,
+                name,
+            })
+        })
+        .filter(|entry| entry.name != "." && entry.name != "..")
+        .collect()
+}
+
+// Queue the requests to free an inode, and everything in it when it's a directory
+fn free_inode(
+    image: &Utf8Path,
+    inode: u64,
+    is_dir: bool,
+    requests: &mut Vec<String>,
+    seen: &mut HashSet<u64>,
+) -> Result<(), BuildError> {
+    if inode < EXT4_FIRST_INODE {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' links to reserved inode: {}", image, inode),
+        )
+        .into());
+    }
+    if !seen.insert(inode) {
+        return Ok(());
+    }
+
+    if is_dir {
+        for entry in list(image, &format!("<{}>", inode))? {
+            free_inode(image, entry.inode, entry.is_dir, requests, seen)?;
+        }
+    }
+    requests.push(format!("kill_file <{}>", inode));
+    requests.push(format!("clri <{}>", inode));
+    Ok(())
+}
+
+// Build a seed cache from a directory with a subdirectory per cache root, e.g. `docker`
+pub fn from_dir(
+    source_dir: &Utf8Path,
+    output_path: &Utf8Path,
+    size: ImageSize,
+    headroom: Headroom,
+) -> Result<(), BuildError> {
+    guard::handle_signals()?;
+    let work_dir = WorkDir::new(WORK_PATH, "seed")?;
+    let work_path = work_dir.path();
+
+    let size_mb = size.resolve(headroom, || fs::dir_size(source_dir))?;
+    let inodes = inode_count(size_mb, fs::dir_inodes(source_dir)?);
+    debug!(
+        "Creating seed cache in: '{}' with size: {}MB",
+        work_path, size_mb
+    );
+    let image_path = qemu::create_fs(work_path, size_mb)?;
+
+    debug!("Creating ext4 filesystem from: '{}'", source_dir);
+    fs::mkfs_ext4_from_dir(&image_path, source_dir, Some(inodes))?;
+    guard::check_interrupted()?;
+
+    debug!("Copying image from: '{}' to: '{}'", image_path, output_path);
+    fs::clone_file(&image_path, output_path, fs::CloneStrategy::Reflink)?;
+
+    debug!("Done!");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_entries() {
+        let output = "/12/040755/0/0/.//\n\
+                      /2/040755/0/0/..//\n\
+                      /13/100644/0/0/docker/2/\n\
+                      /14/100644/0/0/we ird/2/\n\
+                      /15/040755/1001/1001/work//\n\
+                      \n";
+
+        assert_eq!(
+            parse_entries(output),
+            vec![
+                Entry {
+                    inode: 13,
+                    is_dir: false,
+                    name: "docker".to_string()
+                },
+                Entry {
+                    inode: 14,
+                    is_dir: false,
+                    name: "we ird".to_string()
+                },
+                Entry {
+                    inode: 15,
+                    is_dir: true,
+                    name: "work".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_IMAGE_SIZE_GB: u64 = 10;
+const DEFAULT_HEADROOM_PCT: u64 = 20;
+// Room for the filesystem itself, its journal and inode tables
+const FS_OVERHEAD_MB: u64 = 256;
+// Automatically sized images are rounded up to this
+const ROUND_MB: u64 = 64;
+// What `mkfs.ext4` gives every inode by default, and the extra inodes on top of the contents
+const BYTES_PER_INODE: u64 = 16 * 1024;
+const INODE_HEADROOM_PCT: u64 = 20;
+
+// The size of an image to build, `auto` sizes it to its contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSize {
+    Auto,
+    Megabytes(u64),
+}
+
+impl Default for ImageSize {
+    fn default() -> Self {
+        ImageSize::Megabytes(DEFAULT_IMAGE_SIZE_GB * 1024)
+    }
+}
+
+impl ImageSize {
+    // The size in megabytes, measuring the contents only when we size automatically
+    pub fn resolve<E>(
+        &self,
+        headroom: Headroom,
+        content_bytes: impl FnOnce() -> Result<u64, E>,
+    ) -> Result<u64, E> {
+        match self {
+            ImageSize::Megabytes(megabytes) => Ok(*megabytes),
+            ImageSize::Auto => Ok(auto_size_mb(content_bytes()?, headroom)),
+        }
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageSize::Auto => write!(f, "auto"),
+            ImageSize::Megabytes(megabytes) => write_megabytes(f, *megabytes),
+        }
+    }
+}
+
+impl FromStr for ImageSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ImageSize::Auto),
+            _ => match parse_megabytes(s)? {
+                0 => Err(format!("Invalid size: {}", s)),
+                megabytes => Ok(ImageSize::Megabytes(megabytes)),
+            },
+        }
+    }
+}
+
+// Extra space on top of the contents of an automatically sized image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Headroom {
+    Percent(u64),
+    Megabytes(u64),
+}
+
+impl Default for Headroom {
+    fn default() -> Self {
+        Headroom::Percent(DEFAULT_HEADROOM_PCT)
+    }
+}
+
+impl fmt::Display for Headroom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Headroom::Percent(pct) => write!(f, "{}%", pct),
+            Headroom::Megabytes(megabytes) => write_megabytes(f, *megabytes),
+        }
+    }
+}
+
+impl FromStr for Headroom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(pct) => pct
+                .parse()
+                .map(Headroom::Percent)
+                .map_err(|_| format!("Invalid headroom: {}", s)),
+            None => Ok(Headroom::Megabytes(parse_megabytes(s)?)),
+        }
+    }
+}
+
+fn write_megabytes(f: &mut fmt::Formatter, megabytes: u64) -> fmt::Result {
+    match megabytes {
+        0 => write!(f, "0M"),
+        _ if megabytes.is_multiple_of(1024) => write!(f, "{}G", megabytes / 1024),
+        _ => write!(f, "{}M", megabytes),
+    }
+}
+
+// Parse a size like `512M`, `20G` or `1T`, a plain number is in gigabytes
+fn parse_megabytes(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'M' | 'm')) => (&s[..i], 1),
+        Some((i, 'G' | 'g')) => (&s[..i], 1024),
+        Some((i, 'T' | 't')) => (&s[..i], 1024 * 1024),
+        _ => (s, 1024),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size: {}", s))
+}
+
+// The inodes for an image of the given size, the default for its size unless its contents
+// (plus some headroom) need more
+pub fn inode_count(size_mb: u64, content_inodes: u64) -> u64 {
+    let default = size_mb.saturating_mul(1024 * 1024) / BYTES_PER_INODE;
+    let needed = content_inodes.saturating_mul(100 + INODE_HEADROOM_PCT) / 100;
+    default.max(needed)
+}
+
+// The contents plus headroom and room for the filesystem, rounded up
+fn auto_size_mb(content_bytes: u64, headroom: Headroom) -> u64 {
+    let content_mb = content_bytes.div_ceil(1024 * 1024);
+    let headroom_mb = match headroom {
+        Headroom::Percent(pct) => (content_mb * pct).div_ceil(100),
+        Headroom::Megabytes(megabytes) => megabytes,
+    };
+
+    (content_mb + headroom_mb + FS_OVERHEAD_MB).div_ceil(ROUND_MB) * ROUND_MB
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_size() {
+        assert_eq!("auto".parse(), Ok(ImageSize::Auto));
+        assert_eq!("512M".parse(), Ok(ImageSize::Megabytes(512)));
+        assert_eq!("20G".parse(), Ok(ImageSize::Megabytes(20 * 1024)));
+        assert_eq!("300".parse(), Ok(ImageSize::Megabytes(300 * 1024)));
+        assert_eq!("1t".parse(), Ok(ImageSize::Megabytes(1024 * 1024)));
+        assert!("0G".parse::<ImageSize>().is_err());
+        assert!("big".parse::<ImageSize>().is_err());
+        assert!("99999999999999999T".parse::<ImageSize>().is_err());
+        assert!("99999999999999999G".parse::<Headroom>().is_err());
+        assert_eq!(ImageSize::default().to_string(), "10G");
+        assert_eq!(ImageSize::Megabytes(1536).to_string(), "1536M");
+    }
+
+    #[test]
+    fn test_parse_headroom() {
+        assert_eq!("25%".parse(), Ok(Headroom::Percent(25)));
+        assert_eq!("2G".parse(), Ok(Headroom::Megabytes(2048)));
+        assert_eq!("0M".parse(), Ok(Headroom::Megabytes(0)));
+        assert!("%".parse::<Headroom>().is_err());
+    }
+
+    #[test]
+    fn test_auto_size() {
+        let content_bytes = 1000 * 1024 * 1024;
+
+        // 1000M + 200M headroom + 256M overhead, rounded up to 1472M
+        assert_eq!(auto_size_mb(content_bytes, Headroom::Percent(20)), 1472);
+        assert_eq!(auto_size_mb(content_bytes, Headroom::Megabytes(512)), 1792);
+        assert_eq!(
+            ImageSize::Megabytes(100).resolve(Headroom::default(), || Err("not measured")),
+            Ok(100)
+        );
+    }
+
+    #[test]
+    fn test_inode_count() {
+        assert_eq!(inode_count(1024, 1000), 65536);
+        assert_eq!(inode_count(1024, 100_000), 120_000);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::BuildError;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use std::fmt::Write;
+
+// Where the extra scripts are copied to in the image, they're removed once they ran
+const SCRIPTS_PATH: &str = "/tmp/actions-runner-scripts";
+
+// What every runner image needs, the rest of the boilerplate of `example/Dockerfile`.
+// udev is needed for booting a "real" VM, setting up the ttyS0 console properly,
+// kmod for modprobing modules and systemd for running as PID 1 as /sbin/init.
+const BASE_PACKAGES: &[&str] = &[
+    "bash",
+    "ca-certificates",
+    "curl",
+    "dbus",
+    "git",
+    "gnupg",
+    "iproute2",
+    "iputils-ping",
+    "jq",
+    "kmod",
+    "locales",
+    "lsb-release",
+    "net-tools",
+    "sudo",
+    "systemd",
+    "tar",
+    "tzdata",
+    "udev",
+    "unzip",
+    "util-linux",
+];
+
+// A declarative description of a runner image, rendered into a Dockerfile. The base image
+// has to be Debian or Ubuntu based.
+//
+// ```toml
+// base_image = "ubuntu:22.04"
+// packages = ["build-essential", "libyaml-dev"]
+// runner_version = "2.311.0"
+// docker = true
+// scripts = ["install-chrome.sh"]
+// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BuildSpec {
+    pub base_image: String,
+    #[serde(default)]
+    pub packages: Vec<String>,
+    // The latest release of the GitHub runner by default
+    pub runner_version: Option<String>,
+    // Install Docker in the VM, and add the runner to the `docker` group
+    #[serde(default)]
+    pub docker: bool,
+    // Run as root in this order after everything else, relative to the build context
+    #[serde(default)]
+    pub scripts: Vec<Utf8PathBuf>,
+}
+
+impl BuildSpec {
+    pub fn from_file(path: impl AsRef<Utf8Path>) -> Result<Self, BuildError> {
+        let spec_str = std::fs::read_to_string(path.as_ref())?;
+        let spec: Self = toml::from_str(&spec_str)
+            .map_err(|e| BuildError::Spec(format!("{}: {}", path.as_ref(), e)))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    // Everything ends up in shell commands, so only allow what they can't be abused with
+    fn validate(&self) -> Result<(), BuildError> {
+        if self.base_image.is_empty() || self.base_image.chars().any(char::is_whitespace) {
+            return Err(BuildError::Spec(format!(
+                "Invalid base image: '{}'",
+                self.base_image
+            )));
+        }
+        for package in &self.packages {
+            let valid = !package.is_empty()
+                && !package.starts_with('-')
+                && package
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-' | ':' | '='));
+            if !valid {
+                return Err(BuildError::Spec(format!("Invalid package: '{}'", package)));
+            }
+        }
+        if let Some(ref version) = self.runner_version {
+            if version.is_empty() || !version.chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return Err(BuildError::Spec(format!(
+                    "Invalid runner version: '{}'",
+                    version
+                )));
+            }
+        }
+        for script in &self.scripts {
+            let in_context = script
+                .components()
+                .all(|component| matches!(component, Utf8Component::Normal(_)));
+            if !in_context
+                || script.file_name().is_none()
+                || script.as_str().chars().any(char::is_whitespace)
+            {
+                return Err(BuildError::Spec(format!(
+                    "Scripts have to be in the build context: '{}'",
+                    script
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render(&self) -> String {
+        let mut dockerfile = String::new();
+        let _ = self.write_dockerfile(&mut dockerfile);
+        dockerfile
+    }
+
+    fn write_dockerfile(&self, f: &mut String) -> std::fmt::Result {
+        writeln!(f, "# Rendered from a build spec by actions-runner")?;
+        writeln!(f, "FROM {}", self.base_image)?;
+        writeln!(f)?;
+
+        let mut packages: Vec<&str> = BASE_PACKAGES.to_vec();
+        packages.extend(self.packages.iter().map(String::as_str));
+        packages.sort_unstable();
+        packages.dedup();
+        writeln!(
+            f,
+            "RUN apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y \\"
+        )?;
+        for package in packages {
+            writeln!(f, "    {} \\", package)?;
+        }
+        writeln!(f, "  && rm -rf /var/lib/apt/lists/*")?;
+        writeln!(f)?;
+
+        writeln!(
+            f,
+            "RUN sed -i '/en_US.UTF-8/s/^# //g' /etc/locale.gen && locale-gen"
+        )?;
+        writeln!(f, "ENV LANG=en_US.UTF-8")?;
+        writeln!(f, "ENV LANGUAGE=en_US:en")?;
+        writeln!(f, "ENV LC_ALL=en_US.UTF-8")?;
+        writeln!(f)?;
+
+        if self.docker {
+            writeln!(f, "RUN . /etc/os-release \\")?;
+            writeln!(f, "  && mkdir -m 0755 -p /etc/apt/keyrings \\")?;
+            writeln!(f, "  && curl -fsSL https://download.docker.com/linux/$ID/gpg | gpg --dearmor -o /etc/apt/keyrings/docker.gpg \\")?;
+            writeln!(f, "  && echo \"deb [arch=$(dpkg --print-architecture) signed-by=/etc/apt/keyrings/docker.gpg] https://download.docker.com/linux/$ID $VERSION_CODENAME stable\" > /etc/apt/sources.list.d/docker.list \\")?;
+            writeln!(f, "  && apt-get update \\")?;
+            writeln!(f, "  && apt-get install -y docker-ce docker-ce-cli containerd.io docker-buildx-plugin docker-compose-plugin \\")?;
+            writeln!(f, "  && rm -rf /var/lib/apt/lists/* \\")?;
+            writeln!(f, "  && systemctl enable docker \\")?;
+            writeln!(
+                f,
+                "  && update-alternatives --set iptables /usr/sbin/iptables-legacy"
+            )?;
+            writeln!(f)?;
+        }
+
+        let groups = if self.docker { " -G docker" } else { "" };
+        writeln!(f, "RUN groupadd user \\")?;
+        writeln!(
+            f,
+            "  && useradd -m -d /home/runner -s /bin/bash -g user{} runner",
+            groups
+        )?;
+        writeln!(f)?;
+
+        writeln!(f, "# Actions uses this for precompiled binaries")?;
+        writeln!(f, "RUN mkdir -p /opt/hostedtoolcache \\")?;
+        writeln!(f, "  && chown -R runner:user /opt/hostedtoolcache \\")?;
+        writeln!(f, "  && chmod g+rwx /opt/hostedtoolcache")?;
+        writeln!(f)?;
+
+        writeln!(f, "WORKDIR /home/runner")?;
+        match self.runner_version {
+            Some(ref version) => writeln!(f, "RUN GITHUB_RUNNER_VERSION={} \\", version)?,
+            None => writeln!(f, "RUN GITHUB_RUNNER_VERSION=$(curl -fsSL https://api.github.com/repos/actions/runner/releases/latest | jq -r '.tag_name[1:]') \\")?,
+        }
+        writeln!(f, "  && case $(dpkg --print-architecture) in arm64) RUNNER_ARCH=arm64 ;; *) RUNNER_ARCH=x64 ;; esac \\")?;
+        writeln!(f, "  && curl -fsSL https://github.com/actions/runner/releases/download/v${{GITHUB_RUNNER_VERSION}}/actions-runner-linux-${{RUNNER_ARCH}}-${{GITHUB_RUNNER_VERSION}}.tar.gz | tar zx \\")?;
+        writeln!(f, "  && chown -R runner:user /home/runner")?;
+
+        for (idx, script) in self.scripts.iter().enumerate() {
+            let target = format!(
+                "{}/{:02}-{}",
+                SCRIPTS_PATH,
+                idx,
+                script.file_name().unwrap_or_default()
+            );
+            writeln!(f)?;
+            writeln!(f, "COPY {} {}", script, target)?;
+            writeln!(f, "RUN bash {} && rm {}", target, target)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let spec: BuildSpec = toml::from_str(
+            r#"
+            base_image = "ubuntu:22.04"
+            packages = ["build-essential", "jq"]
+            runner_version = "2.311.0"
+            docker = true
+            scripts = ["scripts/install-chrome.sh"]
+            "#,
+        )
+        .expect("Could not parse spec");
+        spec.validate().expect("Invalid spec");
+        let dockerfile = spec.render();
+
+        assert!(dockerfile.contains("FROM ubuntu:22.04\n"));
+        assert!(dockerfile.contains("    build-essential \\\n"));
+        assert_eq!(dockerfile.matches("    jq \\\n").count(), 1);
+        assert!(dockerfile.contains("systemctl enable docker"));
+        assert!(dockerfile.contains("-g user -G docker runner\n"));
+        assert!(dockerfile.contains("RUN GITHUB_RUNNER_VERSION=2.311.0 \\\n"));
+        assert!(dockerfile.contains(
+            "COPY scripts/install-chrome.sh /tmp/actions-runner-scripts/00-install-chrome.sh\n"
+        ));
+
+        let spec: BuildSpec = toml::from_str(r#"base_image = "debian:bookworm""#).unwrap();
+        let dockerfile = spec.render();
+        assert!(!dockerfile.contains("docker-ce"));
+        assert!(dockerfile.contains("-g user runner\n"));
+        assert!(dockerfile.contains("releases/latest"));
+        assert!(!dockerfile.contains("COPY"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let spec = |s: &str| toml::from_str::<BuildSpec>(s).unwrap().validate();
+
+        assert!(spec(r#"base_image = "ubuntu:22.04""#).is_ok());
+        assert!(spec(r#"base_image = "ubuntu:22.04 AS base""#).is_err());
+        assert!(spec("base_image = \"ubuntu\"\npackages = [\"jq; rm -rf /\"]").is_err());
+        assert!(spec("base_image = \"ubuntu\"\npackages = [\"--allow-unauthenticated\"]").is_err());
+        assert!(spec("base_image = \"ubuntu\"\nrunner_version = \"latest\"").is_err());
+        assert!(spec("base_image = \"ubuntu\"\nscripts = [\"../setup.sh\"]").is_err());
+        assert!(spec("base_image = \"ubuntu\"\nscripts = [\"/setup.sh\"]").is_err());
+        assert!(
+            toml::from_str::<BuildSpec>("base_image = \"ubuntu\"\npackage = [\"jq\"]").is_err()
+        );
+    }
+}
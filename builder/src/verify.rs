@@ -0,0 +1,232 @@
+use crate::{guard, BuildError, WORK_PATH};
+use camino::Utf8Path;
+use log::*;
+use std::fmt;
+use std::process::Command;
+use util::{exec, fs};
+
+const RUNNER_USER: &str = "runner";
+const DOCKER_GROUP: &str = "docker";
+const RUNNER_FILES: [&str; 2] = ["/home/runner/config.sh", "/home/runner/run.sh"];
+const INIT_PATH: &str = "/sbin/init";
+const ACTIONS_INIT_PATH: &str = "/sbin/actions-init";
+
+// A runner prerequisite the image doesn't meet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    MissingUser(String),
+    MissingFile(String),
+    NotInGroup { user: String, group: String },
+    ActionsInitMismatch,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MissingUser(user) => write!(f, "There's no `{}` user", user),
+            Problem::MissingFile(path) => write!(f, "'{}' is missing", path),
+            Problem::NotInGroup { user, group } => {
+                write!(f, "The `{}` user isn't in the `{}` group", user, group)
+            }
+            Problem::ActionsInitMismatch => write!(
+                f,
+                "'{}' doesn't match the builder binary",
+                ACTIONS_INIT_PATH
+            ),
+        }
+    }
+}
+
+pub fn describe(problems: &[Problem]) -> String {
+    problems
+        .iter()
+        .map(Problem::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Check an (unmounted) ext4 image for everything the runner needs, and that it has our
+// own binary as `actions-init`. Returns every prerequisite that isn't met.
+pub fn verify(image_path: &Utf8Path, own_path: &Utf8Path) -> Result<Vec<Problem>, BuildError> {
+    let image = Image { path: image_path };
+    let mut problems = Vec::new();
+
+    let passwd = image.read("/etc/passwd")?.unwrap_or_default();
+    let group = image.read("/etc/group")?;
+    problems.extend(check_accounts(&passwd, group.as_deref()));
+
+    for path in RUNNER_FILES.iter().chain(&[INIT_PATH, ACTIONS_INIT_PATH]) {
+        if !image.exists(path)? {
+            problems.push(Problem::MissingFile(path.to_string()));
+        }
+    }
+
+    if image.exists(ACTIONS_INIT_PATH)? {
+        let work_dir = guard::WorkDir::new(WORK_PATH, "verify")?;
+        let actions_init_path = work_dir.path().join("actions-init");
+        image.dump(ACTIONS_INIT_PATH, &actions_init_path)?;
+        if fs::sha256(&actions_init_path)? != fs::sha256(own_path)? {
+            problems.push(Problem::ActionsInitMismatch);
+        }
+    }
+
+    Ok(problems)
+}
+
+// The runner user has to exist, and if the image has Docker it has to be allowed to use it
+fn check_accounts(passwd: &str, group: Option<&str>) -> Vec<Problem> {
+    let Some(user_gid) = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&RUNNER_USER))
+        .and_then(|fields| fields.get(3).map(|gid| gid.to_string()))
+    else {
+        return vec![Problem::MissingUser(RUNNER_USER.to_string())];
+    };
+
+    let docker_group = group.and_then(|group| {
+        group
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.first() == Some(&DOCKER_GROUP))
+            .map(|fields| {
+                fields
+                    .iter()
+                    .map(|field| field.to_string())
+                    .collect::<Vec<_>>()
+            })
+    });
+
+    match docker_group {
+        Some(fields) => {
+            let is_primary = fields.get(2) == Some(&user_gid);
+            let is_member = fields
+                .get(3)
+                .map(|members| members.split(',').any(|member| member == RUNNER_USER))
+                .unwrap_or(false);
+            if is_primary || is_member {
+                vec![]
+            } else {
+                vec![Problem::NotInGroup {
+                    user: RUNNER_USER.to_string(),
+                    group: DOCKER_GROUP.to_string(),
+                }]
+            }
+        }
+        // Docker isn't installed in the image
+        None => vec![],
+    }
+}
+
+// Reads an ext4 image with `debugfs`, so it doesn't need to be mounted
+struct Image<'a> {
+    path: &'a Utf8Path,
+}
+
+impl Image<'_> {
+    fn debugfs(&self, request: &str) -> Result<String, BuildError> {
+        trace!("Running debugfs request: '{}' on: '{}'", request, self.path);
+        let output = exec(Command::new("debugfs").args(["-R", request, self.path.as_str()]))?;
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    // `debugfs` doesn't fail on missing files, it only reports them on stderr
+    fn exists(&self, path: &str) -> Result<bool, BuildError> {
+        Ok(self
+            .debugfs(&format!("stat {}", path))?
+            .starts_with("Inode:"))
+    }
+
+    fn read(&self, path: &str) -> Result<Option<String>, BuildError> {
+        if !self.exists(path)? {
+            return Ok(None);
+        }
+        self.debugfs(&format!("cat {}", path)).map(Some)
+    }
+
+    fn dump(&self, path: &str, target: &Utf8Path) -> Result<(), BuildError> {
+        self.debugfs(&format!("dump {} {}", path, target))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str =
+        "root:x:0:0:root:/root:/bin/bash\nrunner:x:1001:1001::/home/runner:/bin/bash\n";
+
+    #[test]
+    fn test_check_accounts() {
+        assert_eq!(
+            check_accounts("root:x:0:0:root:/root:/bin/bash\n", None),
+            vec![Problem::MissingUser("runner".to_string())]
+        );
+        assert_eq!(check_accounts(PASSWD, None), vec![]);
+        assert_eq!(check_accounts(PASSWD, Some("root:x:0:\n")), vec![]);
+        assert_eq!(
+            check_accounts(PASSWD, Some("root:x:0:\ndocker:x:999:root\n")),
+            vec![Problem::NotInGroup {
+                user: "runner".to_string(),
+                group: "docker".to_string()
+            }]
+        );
+        assert_eq!(
+            check_accounts(PASSWD, Some("docker:x:999:root,runner\n")),
+            vec![]
+        );
+        assert_eq!(check_accounts(PASSWD, Some("docker:x:1001:\n")), vec![]);
+    }
+
+    // Needs e2fsprogs, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_verify() {
+        let work_dir = guard::WorkDir::new(WORK_PATH, "test-verify").unwrap();
+        let own_path = work_dir.path().join("actions-runner");
+        std::fs::write(&own_path, "builder").unwrap();
+
+        let root_path = work_dir.path().join("rootfs");
+        for dir in ["etc", "home/runner", "usr/sbin"] {
+            std::fs::create_dir_all(root_path.join(dir)).unwrap();
+        }
+        std::os::unix::fs::symlink("usr/sbin", root_path.join("sbin")).unwrap();
+        std::fs::write(root_path.join("etc/passwd"), PASSWD).unwrap();
+        std::fs::write(root_path.join("etc/group"), "docker:x:999:\n").unwrap();
+        std::fs::write(root_path.join("home/runner/run.sh"), "").unwrap();
+        std::fs::write(root_path.join("usr/sbin/init"), "").unwrap();
+        std::fs::write(root_path.join("usr/sbin/actions-init"), "other").unwrap();
+
+        let image_path = work_dir.path().join("rootfs.ext4");
+        std::fs::File::create(&image_path)
+            .unwrap()
+            .set_len(16 * 1024 * 1024)
+            .unwrap();
+        fs::mkfs_ext4_from_dir(&image_path, &root_path, None).unwrap();
+
+        assert_eq!(
+            verify(&image_path, &own_path).unwrap(),
+            vec![
+                Problem::NotInGroup {
+                    user: "runner".to_string(),
+                    group: "docker".to_string()
+                },
+                Problem::MissingFile("/home/runner/config.sh".to_string()),
+                Problem::ActionsInitMismatch,
+            ]
+        );
+
+        std::fs::write(&own_path, "other").unwrap();
+        assert_eq!(
+            verify(&image_path, &own_path).unwrap(),
+            vec![
+                Problem::NotInGroup {
+                    user: "runner".to_string(),
+                    group: "docker".to_string()
+                },
+                Problem::MissingFile("/home/runner/config.sh".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// The filesystems we can format and mount disks with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+}
+
+impl DiskFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskFormat::Ext4 => "ext4",
+            DiskFormat::Xfs => "xfs",
+            DiskFormat::Btrfs => "btrfs",
+        }
+    }
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for DiskFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(DiskFormat::Ext4),
+            "xfs" => Ok(DiskFormat::Xfs),
+            "btrfs" => Ok(DiskFormat::Btrfs),
+            _ => Err(format!("Unknown disk format: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+// Events the guest reports to the manager over vsock, one JSON object per line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GuestEvent {
+    Booted,
+    Registered,
+    JobStarted {
+        job: String,
+    },
+    JobFinished {
+        result: String,
+    },
+    CacheUsage {
+        used_bytes: u64,
+        total_bytes: u64,
+    },
+    CacheEvicted {
+        name: String,
+        size_bytes: u64,
+        over_quota: bool,
+    },
+    CacheWiped {
+        used_pct: u8,
+    },
+    ShuttingDown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_event_json() {
+        let event = GuestEvent::JobStarted {
+            job: "build".to_string(),
+        };
+        let json = serde_json::to_string(&event).expect("Could not serialize event");
+
+        assert_eq!(json, r#"{"event":"job_started","job":"build"}"#);
+        assert_eq!(
+            serde_json::from_str::<GuestEvent>(r#"{"event":"shutting_down"}"#)
+                .expect("Could not parse event"),
+            GuestEvent::ShuttingDown
+        );
+    }
+}
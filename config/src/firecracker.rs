@@ -8,6 +8,14 @@ pub struct FirecrackerConfig {
     pub drives: Vec<Drive>,
     pub network_interfaces: Vec<NetworkInterface>,
     pub machine_config: MachineConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mmds_config: Option<MmdsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock: Option<Vsock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logger: Option<Logger>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Metrics>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,3 +46,65 @@ pub struct MachineConfig {
     pub vcpu_count: u32,
     pub mem_size_mib: u32,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Vsock {
+    pub guest_cid: u32,
+    pub uds_path: Utf8PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Logger {
+    pub log_path: Utf8PathBuf,
+    pub level: String,
+    pub show_level: bool,
+    pub show_log_origin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metrics {
+    pub metrics_path: Utf8PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MmdsConfig {
+    pub version: String,
+    pub network_interfaces: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstanceAction {
+    pub action_type: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VmState {
+    pub state: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotCreate {
+    pub snapshot_type: String,
+    pub snapshot_path: Utf8PathBuf,
+    pub mem_file_path: Utf8PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotLoad {
+    pub snapshot_path: Utf8PathBuf,
+    pub mem_backend: MemBackend,
+    pub resume_vm: bool,
+    pub network_overrides: Vec<NetworkOverride>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemBackend {
+    pub backend_type: String,
+    pub backend_path: Utf8PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetworkOverride {
+    pub iface_id: String,
+    pub host_dev_name: String,
+}
@@ -0,0 +1,85 @@
+use crate::ConfigError;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Where the manifest is written inside the image, relative to its root
+pub const IMAGE_MANIFEST_PATH: &str = "etc/actions-runner/image.json";
+
+// Where a built image came from, written inside the image and next to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageManifest {
+    pub source: String,
+    // Of the Dockerfile the image was built from, for a spec the Dockerfile it rendered
+    pub dockerfile_sha256: Option<String>,
+    pub image_id: String,
+    // RFC 3339
+    pub built_at: String,
+    pub builder_version: String,
+    pub runner_version: Option<String>,
+    pub size_bytes: u64,
+}
+
+impl ImageManifest {
+    // The manifest next to an image, e.g. `result.img.json`
+    pub fn sidecar_path(image_path: impl AsRef<Utf8Path>) -> Utf8PathBuf {
+        format!("{}.json", image_path.as_ref()).into()
+    }
+
+    pub fn from_file(path: impl AsRef<Utf8Path>) -> Result<Self, ConfigError> {
+        let manifest_str = std::fs::read_to_string(path.as_ref())?;
+        Ok(serde_json::from_str(&manifest_str)?)
+    }
+
+    pub fn write(&self, path: impl AsRef<Utf8Path>) -> Result<(), ConfigError> {
+        std::fs::write(path.as_ref(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for ImageManifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "built from {} ({}) at {} by builder {}, runner {}, {}MB",
+            self.source,
+            self.image_id,
+            self.built_at,
+            self.builder_version,
+            self.runner_version.as_deref().unwrap_or("unknown"),
+            self.size_bytes / 1024 / 1024
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_file() {
+        let path = Utf8PathBuf::from("/tmp/test_manifest_file.img");
+        let manifest = ImageManifest {
+            source: "Dockerfile 'example/Dockerfile'".to_string(),
+            dockerfile_sha256: Some("abcd".to_string()),
+            image_id: "sha256:1234".to_string(),
+            built_at: "2024-01-20T12:00:00+00:00".to_string(),
+            builder_version: "0.1.0".to_string(),
+            runner_version: Some("2.311.0".to_string()),
+            size_bytes: 10 * 1024 * 1024 * 1024,
+        };
+
+        let sidecar_path = ImageManifest::sidecar_path(&path);
+        assert_eq!(sidecar_path, "/tmp/test_manifest_file.img.json");
+
+        manifest
+            .write(&sidecar_path)
+            .expect("Could not write manifest");
+        assert_eq!(
+            ImageManifest::from_file(&sidecar_path).expect("Could not read manifest"),
+            manifest
+        );
+
+        let _ = std::fs::remove_file(&sidecar_path);
+    }
+}
@@ -1,14 +1,32 @@
 use thiserror::*;
 
-
+pub mod disk;
+pub mod events;
 pub mod firecracker;
+pub mod image;
 pub mod manager;
+pub mod mmds;
 
 pub const DEFAULT_BOOT_ARGS: &str =
-    "random.trust_cpu=on reboot=k panic=1 pci=off overlay_root=vdb init=/sbin/actions-init";
+    "random.trust_cpu=on reboot=k panic=1 pci=off init=/sbin/actions-init";
 pub const NETWORK_MAGIC_MAC_START: &str = "06:00";
 pub const NETWORK_MASK_SHORT: u8 = 30;
 pub const NETWORK_MAX_ALLOCATIONS: u8 = 200;
+pub const MMDS_ADDRESS: &str = "169.254.169.254";
+// Printed on the serial console by a snapshot template once it's ready to be snapshotted
+pub const GUEST_READY_MARKER: &str = "actions-runner: guest ready";
+// The guest reaches the host on its well-known CID, Firecracker forwards the
+// connections to `<uds_path>_<port>` on the host.
+pub const VSOCK_GUEST_CID: u32 = 3;
+pub const VSOCK_HOST_CID: u32 = 2;
+pub const GUEST_EVENTS_PORT: u32 = 1025;
+// Where the cache disk is mounted in the guest
+pub const GUEST_CACHE_PATH: &str = "/cache";
+// The guest touches a marker per cache root in here, every time it mounts the cache
+pub const CACHE_LAST_USED_DIR: &str = ".last-used";
+// Where the read-only seed cache is mounted in the guest, and the overlayfs work dirs on the cache
+pub const GUEST_SEED_CACHE_PATH: &str = "/seed-cache";
+pub const CACHE_WORK_DIR: &str = ".work";
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
@@ -16,4 +34,6 @@ pub enum ConfigError {
     Io(#[from] std::io::Error),
     #[error("Config TOML error: {:?}", self)]
     Toml(#[from] toml::de::Error),
+    #[error("JSON error: {:?}", self)]
+    Json(#[from] serde_json::Error),
 }
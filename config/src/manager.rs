@@ -1,6 +1,9 @@
+use crate::disk::DiskFormat;
 use anyhow::Result;
 use camino::Utf8PathBuf;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
 use toml;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -10,6 +13,12 @@ pub struct ManagerConfig {
     pub roles: Vec<Role>,
     pub github_org: String,
     pub github_pat: String,
+    pub jailer: Option<JailerConfig>,
+    // Serve Prometheus metrics on `/metrics` on this address, e.g. `127.0.0.1:9100`
+    pub metrics_address: Option<String>,
+    // Where roles find images by name and tag, e.g. `rootfs_image = "your-project:latest"`
+    #[serde(default = "_default_store_path")]
+    pub store_path: Utf8PathBuf,
 }
 
 impl ManagerConfig {
@@ -20,6 +29,48 @@ impl ManagerConfig {
     }
 }
 
+fn _default_store_path() -> Utf8PathBuf {
+    "/var/lib/actions-runner".into()
+}
+
+fn _default_jailer_binary() -> Utf8PathBuf {
+    "/usr/bin/jailer".into()
+}
+
+fn _default_firecracker_binary() -> Utf8PathBuf {
+    "/usr/bin/firecracker".into()
+}
+
+// Outside of the guests' 172.16.0.0/16 and Docker's default 172.17.0.0/16 and up
+fn _default_veth_network() -> Ipv4Addr {
+    Ipv4Addr::new(10, 200, 0, 0)
+}
+
+const fn _default_jailer_uid_start() -> u32 {
+    10000
+}
+
+const fn _default_jailer_gid_start() -> u32 {
+    10000
+}
+
+// Run every instance through the Firecracker jailer, each instance gets
+// its own chroot, uid/gid (start + instance index), cgroup and network namespace.
+#[derive(Deserialize, Debug, Clone)]
+pub struct JailerConfig {
+    #[serde(default = "_default_jailer_binary")]
+    pub jailer_binary: Utf8PathBuf,
+    #[serde(default = "_default_firecracker_binary")]
+    pub firecracker_binary: Utf8PathBuf,
+    #[serde(default = "_default_jailer_uid_start")]
+    pub uid_start: u32,
+    #[serde(default = "_default_jailer_gid_start")]
+    pub gid_start: u32,
+    // A /16 the network namespaces are connected to the host with, a /24 per instance
+    #[serde(default = "_default_veth_network")]
+    pub veth_network: Ipv4Addr,
+}
+
 const fn _default_overlay_size() -> u32 {
     10 // 10GB
 }
@@ -28,6 +79,45 @@ const fn _default_max_cache_pct() -> u8 {
     90
 }
 
+const fn _default_cache_low_water_pct() -> u8 {
+    70
+}
+
+const fn _default_boot_logs() -> u8 {
+    5
+}
+
+const fn _default_read_only() -> bool {
+    true
+}
+
+// An extra block device attached to every instance of a role, e.g. a toolchain image or test fixtures.
+// Read-only drives are shared by the instances, writable drives are cloned for every boot.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExtraDrive {
+    pub path: Utf8PathBuf,
+    pub mount_point: Utf8PathBuf,
+    #[serde(default = "_default_read_only")]
+    pub read_only: bool,
+    // Firecracker's cache type, `Unsafe` (the default) or `Writeback`
+    pub cache_type: Option<String>,
+}
+
+// What happens to the cache of an instance between boots
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    // Keep the cache between boots and manager restarts, until it's too full
+    Persistent,
+    // Start every boot with an empty cache
+    #[default]
+    Ephemeral,
+    // Start every boot from the role's baseline, the cache of a successful job becomes the new baseline.
+    // Whether a job succeeded is reported by the guest, so a job that can run code in the guest can
+    // promote a cache of its choosing. Only use this for roles that run trusted workflows.
+    Promote,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Role {
     pub name: String,
@@ -45,7 +135,35 @@ pub struct Role {
     #[serde(default = "_default_max_cache_pct")]
     pub max_cache_pct: u8,
     #[serde(default)]
+    pub cache_format: DiskFormat,
+    #[serde(default)]
+    pub cache_policy: CachePolicy,
+    // Wipe the cache by cloning a formatted empty image, instead of running mkfs every time
+    #[serde(default)]
+    pub cache_template: bool,
+    // Trim the cache before every boot, so the space of deleted files is given back to the host
+    #[serde(default)]
+    pub cache_trim: bool,
+    // Cache roots are evicted until the usage is below this, when it went over `max_cache_pct`
+    #[serde(default = "_default_cache_low_water_pct")]
+    pub cache_low_water_pct: u8,
+    // Size limits in GB per cache root, e.g. `docker = 20`
+    #[serde(default)]
+    pub cache_quotas: HashMap<String, u32>,
+    // A read-only image shared by all instances of the role, underneath their own cache
+    pub seed_cache: Option<Utf8PathBuf>,
+    #[serde(default)]
+    pub extra_drives: Vec<ExtraDrive>,
+    #[serde(default)]
     pub labels: Vec<String>,
+    #[serde(default)]
+    pub snapshot: bool,
+    #[serde(default = "_default_boot_logs")]
+    pub boot_logs: u8,
+    // In seconds, how long an instance may run in total
+    pub max_lifetime: Option<u64>,
+    // In seconds, how long a runner may go without picking up a job
+    pub idle_registration_timeout: Option<u64>,
 }
 
 impl Role {
@@ -66,6 +184,105 @@ mod tests {
         assert_eq!(&config.network_interface, "eth0");
     }
 
+    #[test]
+    fn test_jailer_config_defaults() {
+        let config: ManagerConfig = toml::from_str(
+            r#"
+            network_interface="eth0"
+            run_path="/srv"
+            github_pat="ghp_1234567890"
+            github_org="matsimitsu"
+            roles=[]
+
+            [jailer]
+            uid_start=20000
+            "#,
+        )
+        .expect("Could not parse config");
+        let jailer = config.jailer.expect("No jailer config");
+
+        assert_eq!(jailer.uid_start, 20000);
+        assert_eq!(jailer.gid_start, 10000);
+        assert_eq!(jailer.firecracker_binary, "/usr/bin/firecracker");
+        assert_eq!(jailer.veth_network, Ipv4Addr::new(10, 200, 0, 0));
+        assert_eq!(config.store_path, "/var/lib/actions-runner");
+    }
+
+    #[test]
+    fn test_role_cache_format() {
+        let role: Role = toml::from_str(
+            r#"
+            name="test"
+            rootfs_image="rootfs.img"
+            kernel_image="vmlinux.bin"
+            cpus=1
+            memory_size=1
+            cache_size=1
+            instance_count=1
+            cache_format="xfs"
+            "#,
+        )
+        .expect("Could not parse role");
+
+        assert_eq!(role.cache_format, DiskFormat::Xfs);
+        assert!(!role.cache_template);
+        assert_eq!(role.cache_policy, CachePolicy::Ephemeral);
+    }
+
+    #[test]
+    fn test_role_cache_policy() {
+        let role: Role = toml::from_str(
+            r#"
+            name="test"
+            rootfs_image="rootfs.img"
+            kernel_image="vmlinux.bin"
+            cpus=1
+            memory_size=1
+            cache_size=1
+            instance_count=1
+            cache_policy="promote"
+            "#,
+        )
+        .expect("Could not parse role");
+
+        assert_eq!(role.cache_policy, CachePolicy::Promote);
+    }
+
+    #[test]
+    fn test_role_extra_drives() {
+        let role: Role = toml::from_str(
+            r#"
+            name="test"
+            rootfs_image="rootfs.img"
+            kernel_image="vmlinux.bin"
+            cpus=1
+            memory_size=1
+            cache_size=1
+            instance_count=1
+
+            [[extra_drives]]
+            path="/srv/fixtures.ext4"
+            mount_point="/fixtures"
+
+            [[extra_drives]]
+            path="/srv/scratch.ext4"
+            mount_point="/scratch"
+            read_only=false
+            cache_type="Writeback"
+            "#,
+        )
+        .expect("Could not parse role");
+
+        assert_eq!(role.extra_drives.len(), 2);
+        assert!(role.extra_drives[0].read_only);
+        assert_eq!(role.extra_drives[0].cache_type, None);
+        assert!(!role.extra_drives[1].read_only);
+        assert_eq!(
+            role.extra_drives[1].cache_type.as_deref(),
+            Some("Writeback")
+        );
+    }
+
     mod helpers {
         use camino::Utf8PathBuf;
         use std::env::current_dir;
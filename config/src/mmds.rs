@@ -0,0 +1,30 @@
+use crate::disk::DiskFormat;
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// The per-instance identity, passed to instances restored from a snapshot through
+// the Firecracker metadata service (MMDS).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Identity {
+    pub hostname: String,
+    pub timestamp: i64,
+    pub own_address: Ipv4Addr,
+    pub host_address: Ipv4Addr,
+    pub cache_paths: Option<String>,
+    #[serde(default)]
+    pub cache_format: DiskFormat,
+    #[serde(default)]
+    pub seed_cache: Option<String>,
+    #[serde(default)]
+    pub cache_max_pct: Option<u8>,
+    #[serde(default)]
+    pub cache_low_water_pct: Option<u8>,
+    #[serde(default)]
+    pub cache_quotas: Option<String>,
+    #[serde(default)]
+    pub extra_drives: Option<String>,
+    pub github_org: String,
+    pub github_token: String,
+    pub github_runner_name: String,
+    pub github_runner_labels: String,
+}
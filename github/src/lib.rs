@@ -1,13 +1,30 @@
 use anyhow::Result;
 
+use reqwest::{blocking::RequestBuilder, Method};
 use serde::{Deserialize, Serialize};
 
+const RUNNERS_PER_PAGE: usize = 100;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegistrationTokenResult {
     pub token: String,
     pub expires_at: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Runner {
+    pub id: u64,
+    pub name: String,
+    pub status: String,
+    pub busy: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunnersResult {
+    pub total_count: usize,
+    pub runners: Vec<Runner>,
+}
+
 #[derive(Debug, Clone)]
 pub struct GitHub {
     pub org: String,
@@ -24,36 +41,64 @@ impl GitHub {
         }
     }
 
-    pub fn registration_token(&self) -> Result<String> {
-        let registration_token_result = self
-            .client
-            .post(format!(
-                "https://api.github.com/orgs/{}/actions/runners/registration-token",
-                self.org
-            ))
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(
+                method,
+                format!("https://api.github.com/orgs/{}{}", self.org, path),
+            )
             .header("Authorization", format!("Bearer {}", self.pat))
             .header("Accept", "application/vnd.github+json")
             .header("X-GitHub-Api-Version", "2022-11-28")
             .header("User-Agent", "actions-runner")
+    }
+
+    pub fn registration_token(&self) -> Result<String> {
+        let registration_token_result = self
+            .request(Method::POST, "/actions/runners/registration-token")
             .send()?
             .json::<RegistrationTokenResult>()?;
         Ok(registration_token_result.token)
     }
 
+    pub fn runners(&self) -> Result<Vec<Runner>> {
+        let mut runners = Vec::new();
+        for page in 1.. {
+            let result = self
+                .request(
+                    Method::GET,
+                    &format!(
+                        "/actions/runners?per_page={}&page={}",
+                        RUNNERS_PER_PAGE, page
+                    ),
+                )
+                .send()?
+                .error_for_status()?
+                .json::<RunnersResult>()?;
+
+            let count = result.runners.len();
+            runners.extend(result.runners);
+            if count < RUNNERS_PER_PAGE || runners.len() >= result.total_count {
+                break;
+            }
+        }
+        Ok(runners)
+    }
+
+    pub fn runner(&self, runner_name: &str) -> Result<Option<Runner>> {
+        Ok(self
+            .runners()?
+            .into_iter()
+            .find(|runner| runner.name == runner_name))
+    }
+
+    // Runners are removed by id, a runner that isn't registered is already gone
     pub fn remove_runner(&self, runner_name: &str) -> Result<()> {
-        self.client
-            .post(format!(
-                "https://api.github.com/orgs/{}/actions/runners/remove-token",
-                self.org
-            ))
-            .header("Authorization", format!("Bearer {}", self.pat))
-            .header("Accept", "application/vnd.github+json")
-            .header("X-GitHub-Api-Version", "2022-11-28")
-            .header("User-Agent", "actions-runner")
-            .json(&serde_json::json!({
-                "runner_name": runner_name
-            }))
-            .send()?;
+        if let Some(runner) = self.runner(runner_name)? {
+            self.request(Method::DELETE, &format!("/actions/runners/{}", runner.id))
+                .send()?
+                .error_for_status()?;
+        }
         Ok(())
     }
 }
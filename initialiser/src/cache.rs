@@ -1,11 +1,18 @@
+use crate::evict::{self, Eviction, Limits};
+use crate::report;
 use anyhow::Result;
+use camino::Utf8Path;
+use config::{
+    disk::DiskFormat, events::GuestEvent, CACHE_LAST_USED_DIR, CACHE_WORK_DIR,
+    GUEST_CACHE_PATH as CACHE_PATH, GUEST_SEED_CACHE_PATH as SEED_CACHE_PATH,
+};
+use log::*;
 use std::fs::{set_permissions, Permissions};
 use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
 use thiserror::Error;
 use util::{fs, mount, CommandExecutionError};
 
-const CACHE_PATH: &str = "/cache";
-
 #[derive(Error, Debug)]
 pub enum CacheError {
     #[error("IO error: {:?}", self)]
@@ -14,11 +21,32 @@ pub enum CacheError {
     Mount(#[from] CommandExecutionError),
 }
 
-pub fn setup_cache(cache_str: &str) -> Result<(), CacheError> {
+// Mounts the cache, makes room on it within the limits, and links every cache path onto it.
+// With a seed cache, cache paths the seed has data for get an overlay of the instance's cache
+// on top of the seed instead.
+pub fn setup_cache(
+    cache_str: &str,
+    format: DiskFormat,
+    seed_device: Option<&str>,
+    limits: Option<&Limits>,
+) -> Result<(), CacheError> {
     fs::mkdir_p(CACHE_PATH)?;
-    mount::mount_ext4("/dev/vdb", CACHE_PATH)?;
+    mount::mount_disk("/dev/vdb", CACHE_PATH, format)?;
     set_permissions(CACHE_PATH, Permissions::from_mode(0o777))?;
 
+    if let Some(limits) = limits {
+        make_room(limits)?;
+    }
+
+    if let Some(seed_device) = seed_device {
+        fs::mkdir_p(SEED_CACHE_PATH)?;
+        mount::mount_read_only(format!("/dev/{}", seed_device), SEED_CACHE_PATH)?;
+    }
+
+    // The least recently used cache roots are evicted first
+    let last_used_path = format!("{}/{}", CACHE_PATH, CACHE_LAST_USED_DIR);
+    fs::mkdir_p(&last_used_path)?;
+
     let cache_links = cache_str.split(',');
     for cache_link in cache_links {
         let cache_link = cache_link.trim();
@@ -33,8 +61,55 @@ pub fn setup_cache(cache_str: &str) -> Result<(), CacheError> {
         let cache_path = cache_parts[1];
 
         fs::mkdir_p(&cache_root)?;
+        std::fs::write(format!("{}/{}", last_used_path, cache_parts[0]), "")?;
+
+        let seed_root = format!("{}/{}", SEED_CACHE_PATH, cache_parts[0]);
+        if seed_device.is_some() && Path::new(&seed_root).is_dir() {
+            let work_path = format!("{}/{}/{}", CACHE_PATH, CACHE_WORK_DIR, cache_parts[0]);
+            fs::rm_rf(&work_path)?;
+            fs::mkdir_p(&work_path)?;
+            fs::mkdir_p(cache_path)?;
+            mount::mount_overlay(&seed_root, &cache_root, &work_path, cache_path)?;
+            continue;
+        }
+
+        // Make room for the link if something already created an empty directory
+        if std::fs::read_dir(cache_path).is_ok_and(|mut entries| entries.next().is_none()) {
+            std::fs::remove_dir(cache_path)?;
+        }
         symlink(&cache_root, cache_path)?;
     }
 
     Ok(())
 }
+
+// Evict cache roots, and wipe the cache if that wasn't enough. This happens in here rather
+// than on the host, as nothing on the cache can be trusted.
+fn make_room(limits: &Limits) -> Result<(), CacheError> {
+    let cache_path = Utf8Path::new(CACHE_PATH);
+
+    for eviction in evict::evict(cache_path, limits)? {
+        let event = match eviction {
+            Eviction::OverQuota { name, size_bytes } => GuestEvent::CacheEvicted {
+                name,
+                size_bytes,
+                over_quota: true,
+            },
+            Eviction::LeastRecentlyUsed { name, size_bytes } => GuestEvent::CacheEvicted {
+                name,
+                size_bytes,
+                over_quota: false,
+            },
+        };
+        info!("Evicted cache: {:?}", event);
+        report(event);
+    }
+
+    let used_pct = fs::fs_usage(cache_path)?.used_pct();
+    if used_pct > limits.max_pct {
+        info!("Cache is still {}% full, wiping it", used_pct);
+        evict::wipe(cache_path)?;
+        report(GuestEvent::CacheWiped { used_pct });
+    }
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use anyhow::Result;
+use thiserror::Error;
+use util::{fs, mount, CommandExecutionError};
+
+#[derive(Error, Debug)]
+pub enum DriveError {
+    #[error("IO error: {:?}", self)]
+    Io(#[from] std::io::Error),
+    #[error("Could not mount: {:?}", self)]
+    Mount(#[from] CommandExecutionError),
+}
+
+// Mounts the extra drives of the role, given as `<device>:<mount point>:<ro|rw>` separated by commas
+pub fn setup_drives(drives_str: &str) -> Result<(), DriveError> {
+    for drive in drives_str.split(',') {
+        let drive = drive.trim();
+        let (device, mount_point, read_only) = match drive.split(':').collect::<Vec<&str>>()[..] {
+            [device, mount_point, "ro"] => (device, mount_point, true),
+            [device, mount_point, "rw"] => (device, mount_point, false),
+            _ => {
+                return Err(DriveError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid drive: {}", drive),
+                )))
+            }
+        };
+
+        fs::mkdir_p(mount_point)?;
+        let device = format!("/dev/{}", device);
+        if read_only {
+            mount::mount_read_only(&device, mount_point)?;
+        } else {
+            mount::mount_image(&device, mount_point)?;
+        }
+    }
+
+    Ok(())
+}
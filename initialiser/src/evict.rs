@@ -0,0 +1,237 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use config::{CACHE_LAST_USED_DIR as LAST_USED_DIR, CACHE_WORK_DIR};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+use util::fs::{dir_size, fs_usage, rm_rf};
+
+const LOST_AND_FOUND: &str = "lost+found";
+
+// How full the cache may get, from the role's config
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_pct: u8,
+    pub low_water_pct: u8,
+    // In GB per cache root
+    pub quotas: HashMap<String, u32>,
+}
+
+impl Limits {
+    // From the `cache_max_pct`, `cache_low_water_pct` and `cache_quotas` kernel args
+    pub fn parse(
+        max_pct: &str,
+        low_water_pct: &str,
+        quotas: Option<&str>,
+    ) -> std::io::Result<Self> {
+        Ok(Limits {
+            max_pct: max_pct
+                .parse()
+                .map_err(|_| invalid("max cache percentage", max_pct))?,
+            low_water_pct: low_water_pct
+                .parse()
+                .map_err(|_| invalid("cache low-water mark", low_water_pct))?,
+            quotas: parse_quotas(quotas)?,
+        })
+    }
+}
+
+// The quotas the manager passes us, e.g. `docker:20,cargo:5`
+pub fn parse_quotas(quotas: Option<&str>) -> std::io::Result<HashMap<String, u32>> {
+    let mut parsed = HashMap::new();
+    for quota in quotas.unwrap_or_default().split(',').map(str::trim) {
+        if quota.is_empty() {
+            continue;
+        }
+        let (name, size) = quota
+            .split_once(':')
+            .ok_or_else(|| invalid("cache quota", quota))?;
+        let size = size.parse().map_err(|_| invalid("cache quota", quota))?;
+        parsed.insert(name.to_string(), size);
+    }
+    Ok(parsed)
+}
+
+fn invalid(what: &str, value: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid {}: {}", what, value),
+    )
+}
+
+// A directory on the cache disk that backs one of the `cache_paths`, e.g. `docker`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheRoot {
+    pub name: String,
+    pub path: Utf8PathBuf,
+    pub size_bytes: u64,
+    pub last_used: SystemTime,
+}
+
+impl CacheRoot {
+    fn remove(&self, cache_path: &Utf8Path) -> std::io::Result<()> {
+        rm_rf(&self.path)?;
+        rm_rf(cache_path.join(CACHE_WORK_DIR).join(&self.name))?;
+        rm_rf(cache_path.join(LAST_USED_DIR).join(&self.name))
+    }
+}
+
+// Why a cache root was evicted
+#[derive(Debug, Clone, PartialEq)]
+pub enum Eviction {
+    OverQuota { name: String, size_bytes: u64 },
+    LeastRecentlyUsed { name: String, size_bytes: u64 },
+}
+
+// The cache roots on a mounted cache disk, least recently used first
+pub fn cache_roots(cache_path: &Utf8Path) -> std::io::Result<Vec<CacheRoot>> {
+    let mut roots = Vec::new();
+    for entry in cache_path.read_dir_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if [LAST_USED_DIR, CACHE_WORK_DIR, LOST_AND_FOUND].contains(&name)
+            || !entry.file_type()?.is_dir()
+        {
+            continue;
+        }
+
+        // Roots that were never marked are from before we tracked them, they go first
+        let last_used = fs::metadata(cache_path.join(LAST_USED_DIR).join(name))
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH);
+
+        roots.push(CacheRoot {
+            name: name.to_string(),
+            path: entry.path().to_path_buf(),
+            size_bytes: dir_size(entry.path())?,
+            last_used,
+        });
+    }
+
+    // The largest root goes first if they were last used at the same time
+    roots.sort_by(|a, b| {
+        a.last_used
+            .cmp(&b.last_used)
+            .then(b.size_bytes.cmp(&a.size_bytes))
+    });
+    Ok(roots)
+}
+
+// Evict the roots over their quota, and when the cache disk is over its maximum usage the
+// least recently used roots until it's below the low-water mark.
+pub fn evict(cache_path: &Utf8Path, limits: &Limits) -> std::io::Result<Vec<Eviction>> {
+    let low_water_pct = if fs_usage(cache_path)?.used_pct() > limits.max_pct {
+        limits.low_water_pct
+    } else {
+        100
+    };
+    let mut evictions = Vec::new();
+    let mut roots = Vec::new();
+
+    for root in cache_roots(cache_path)? {
+        match limits.quotas.get(&root.name) {
+            Some(quota) if root.size_bytes > *quota as u64 * 1024 * 1024 * 1024 => {
+                root.remove(cache_path)?;
+                evictions.push(Eviction::OverQuota {
+                    name: root.name,
+                    size_bytes: root.size_bytes,
+                });
+            }
+            _ => roots.push(root),
+        }
+    }
+
+    for root in roots {
+        if fs_usage(cache_path)?.used_pct() < low_water_pct {
+            break;
+        }
+        root.remove(cache_path)?;
+        evictions.push(Eviction::LeastRecentlyUsed {
+            name: root.name,
+            size_bytes: root.size_bytes,
+        });
+    }
+
+    Ok(evictions)
+}
+
+// Remove everything from the cache, for when evicting cache roots wasn't enough
+pub fn wipe(cache_path: &Utf8Path) -> std::io::Result<()> {
+    for entry in cache_path.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name() != LOST_AND_FOUND {
+            rm_rf(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use util::{inner, mock_inner, MTX};
+
+    #[test]
+    fn test_cache_roots() {
+        let _m = MTX.lock();
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c).starts_with("du -s -B4096 /tmp/test_cache_roots/"))
+            .times(3)
+            .returning(inner::internal_exec);
+        ctx.expect()
+            .withf(|c| inner::to_string(c).starts_with("rm -rf /tmp/test_cache_roots/"))
+            .times(3)
+            .returning(inner::internal_exec);
+
+        let cache_path: Utf8PathBuf = "/tmp/test_cache_roots".into();
+        let _ = fs::remove_dir_all(&cache_path);
+        fs::create_dir_all(cache_path.join(LAST_USED_DIR)).expect("Could not create dir");
+        for (name, size) in [("docker", 10), ("cargo", 100), ("npm", 1)] {
+            fs::create_dir_all(cache_path.join(name)).expect("Could not create dir");
+            fs::write(cache_path.join(name).join("file"), vec![0u8; size])
+                .expect("Could not write file");
+        }
+        fs::create_dir_all(cache_path.join(LOST_AND_FOUND)).expect("Could not create dir");
+
+        // `npm` was never marked, `docker` was used before `cargo`
+        let marker = |name: &str, age: u64| {
+            let path = cache_path.join(LAST_USED_DIR).join(name);
+            let file = fs::File::create(path).expect("Could not create marker");
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .expect("Could not set mtime");
+        };
+        marker("docker", 60);
+        marker("cargo", 10);
+
+        let roots = cache_roots(&cache_path).expect("Could not list roots");
+        let names: Vec<&str> = roots.iter().map(|root| root.name.as_str()).collect();
+        assert_eq!(names, vec!["npm", "docker", "cargo"]);
+
+        roots[1].remove(&cache_path).expect("Could not remove root");
+        assert!(!cache_path.join("docker").exists());
+        assert!(!cache_path.join(LAST_USED_DIR).join("docker").exists());
+        ctx.checkpoint();
+
+        let _ = fs::remove_dir_all(&cache_path);
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = Limits::parse("90", "70", Some("docker:20, cargo:5")).expect("Invalid limits");
+        assert_eq!(limits.max_pct, 90);
+        assert_eq!(limits.low_water_pct, 70);
+        assert_eq!(
+            limits.quotas,
+            HashMap::from([("docker".to_string(), 20), ("cargo".to_string(), 5)])
+        );
+
+        assert!(Limits::parse("90", "70", None)
+            .expect("Invalid limits")
+            .quotas
+            .is_empty());
+        assert!(Limits::parse("90", "70", Some("docker")).is_err());
+        assert!(Limits::parse("ninety", "70", None).is_err());
+    }
+}
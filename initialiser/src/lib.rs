@@ -1,13 +1,20 @@
 use anyhow::Result;
 use camino::{Utf8Path, Utf8PathBuf};
+use config::{events::GuestEvent, GUEST_READY_MARKER};
 use log::*;
 use std::env;
-use std::fs::copy;
+use std::fs::{copy, write, OpenOptions};
+use std::io::Write;
 use std::os::unix::process::CommandExt;
 use std::process::Command;
+use util::{exec, vsock};
 
 mod cache;
+mod drives;
+mod evict;
+mod mmds;
 mod network;
+mod overlay;
 mod service;
 
 pub struct Initialiser {
@@ -24,11 +31,25 @@ impl Initialiser {
     }
 
     pub fn run(&self) -> Result<()> {
+        debug!("Setup overlay");
+        match env::var("overlay_root") {
+            Ok(device) => match overlay::setup_overlay(&device) {
+                Ok(_) => info!("Overlay setup complete on: /dev/{}", device),
+                Err(e) => {
+                    error!("Overlay setup failed: {}", e);
+                    return Err(e.into());
+                }
+            },
+            Err(_) => {
+                info!("No 'overlay_root' kernel arg found, skipping overlay setup");
+            }
+        }
+
         debug!("Setup network");
         match network::setup_network() {
             Ok(Some(interface)) => info!(
-                "Network setup complete: {} {} > {}",
-                interface.ifname, interface.own_address, interface.host_address
+                "Network setup complete: {} ({}) {} > {}",
+                interface.ifname, interface.mac, interface.own_address, interface.host_address
             ),
             Ok(None) => info!("No magic address found, skipping network setup"),
             Err(e) => {
@@ -46,10 +67,47 @@ impl Initialiser {
             }
         }
 
+        if env::var("snapshot_template").is_ok() {
+            debug!("Setup snapshot template");
+            match self.setup_snapshot_template() {
+                Ok(_) => info!("Snapshot template setup complete"),
+                Err(e) => {
+                    error!("Snapshot template setup failed: {}", e);
+                    return Err(e);
+                }
+            }
+
+            let err = Command::new("/sbin/init").exec();
+            return Err(err.into());
+        }
+
         debug!("Setup cache");
         match env::var("cache_paths") {
             Ok(cache_paths) => {
-                match cache::setup_cache(&cache_paths) {
+                // Older managers don't pass a format, their caches are always ext4
+                let format = env::var("cache_format")
+                    .ok()
+                    .and_then(|format| format.parse().ok())
+                    .unwrap_or_default();
+
+                let seed_cache = env::var("seed_cache").ok();
+
+                // Older managers don't pass limits, nothing is evicted then
+                let limits = match (env::var("cache_max_pct"), env::var("cache_low_water_pct")) {
+                    (Ok(max_pct), Ok(low_water_pct)) => Some(evict::Limits::parse(
+                        &max_pct,
+                        &low_water_pct,
+                        env::var("cache_quotas").ok().as_deref(),
+                    )?),
+                    _ => None,
+                };
+
+                match cache::setup_cache(
+                    &cache_paths,
+                    format,
+                    seed_cache.as_deref(),
+                    limits.as_ref(),
+                ) {
                     Ok(_) => info!("Cache setup complete"),
                     Err(e) => {
                         error!("Cache setup failed: {}", e);
@@ -62,6 +120,20 @@ impl Initialiser {
             }
         }
 
+        debug!("Setup extra drives");
+        match env::var("extra_drives") {
+            Ok(extra_drives) => match drives::setup_drives(&extra_drives) {
+                Ok(_) => info!("Extra drives setup complete"),
+                Err(e) => {
+                    error!("Extra drives setup failed: {}", e);
+                    return Err(e.into());
+                }
+            },
+            Err(_) => {
+                info!("No 'extra_drives' kernel arg found, skipping extra drives setup");
+            }
+        }
+
         debug!("Setup actions-runner");
         match (
             env::var("github_org"),
@@ -94,7 +166,99 @@ impl Initialiser {
             }
         }
 
-        Command::new("/sbin/init").exec();
+        report(GuestEvent::Booted);
+
+        // `exec` only returns if replacing ourselves with init failed
+        let err = Command::new("/sbin/init").exec();
+        Err(err.into())
+    }
+
+    // The cache and the runner are set up after the template is restored, as they
+    // differ per instance.
+    fn setup_snapshot_template(&self) -> Result<()> {
+        debug!("Route metadata service");
+        network::setup_mmds_route()?;
+
+        debug!("Copy self to actions-runner");
+        copy(&self.own_path, Utf8PathBuf::from("/sbin/actions-run"))?;
+
+        debug!("Set snapshot runner init script");
+        service::setup_snapshot_service()?;
+
+        debug!("Symlink init script to start at boot");
+        service::enable_service()?;
+
+        Ok(())
+    }
+
+    // Runs as the first step of the runner service in a snapshot template. Signals the
+    // manager we're ready to be snapshotted, and picks up our identity after we're restored.
+    pub fn restore(&self) -> Result<()> {
+        debug!("Signal guest is ready");
+        let mut console = OpenOptions::new().write(true).open("/dev/console")?;
+        writeln!(console, "{}", GUEST_READY_MARKER)?;
+
+        debug!("Wait for identity");
+        let identity = mmds::wait_for_identity()?;
+        info!("Restored as: {}", identity.hostname);
+
+        debug!("Setup clock");
+        exec(Command::new("date").args(["-s", &format!("@{}", identity.timestamp)]))?;
+
+        debug!("Setup hostname");
+        write("/etc/hostname", format!("{}\n", identity.hostname))?;
+        exec(Command::new("hostname").arg(&identity.hostname))?;
+
+        debug!("Setup network");
+        let interface = network::reconfigure_network(identity.own_address, identity.host_address)?;
+        info!(
+            "Network setup complete: {} ({}) {} > {}",
+            interface.ifname, interface.mac, interface.own_address, interface.host_address
+        );
+
+        debug!("Setup cache");
+        match identity.cache_paths {
+            Some(ref cache_paths) => {
+                let limits = match (identity.cache_max_pct, identity.cache_low_water_pct) {
+                    (Some(max_pct), Some(low_water_pct)) => Some(evict::Limits {
+                        max_pct,
+                        low_water_pct,
+                        quotas: evict::parse_quotas(identity.cache_quotas.as_deref())?,
+                    }),
+                    _ => None,
+                };
+                cache::setup_cache(
+                    cache_paths,
+                    identity.cache_format,
+                    identity.seed_cache.as_deref(),
+                    limits.as_ref(),
+                )?;
+                info!("Cache setup complete");
+            }
+            None => info!("No cache paths in identity, skipping cache setup"),
+        }
+
+        debug!("Setup extra drives");
+        match identity.extra_drives {
+            Some(ref extra_drives) => {
+                drives::setup_drives(extra_drives)?;
+                info!("Extra drives setup complete");
+            }
+            None => info!("No extra drives in identity, skipping extra drives setup"),
+        }
+
+        debug!("Write runner environment");
+        service::write_environment(&identity)?;
+
+        report(GuestEvent::Booted);
+
         Ok(())
     }
 }
+
+// The manager can do without our events, so we only log when they can't be sent
+fn report(event: GuestEvent) {
+    if let Err(e) = vsock::report_event(&event) {
+        warn!("Could not report {:?} to the manager: {}", event, e);
+    }
+}
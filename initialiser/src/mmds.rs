@@ -0,0 +1,49 @@
+use config::{mmds::Identity, MMDS_ADDRESS};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+const MMDS_TOKEN_TTL_SECONDS: &str = "60";
+const MMDS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum MmdsError {
+    #[error("HTTP error: {:?}", self)]
+    Http(#[from] reqwest::Error),
+}
+
+fn fetch_identity(client: &reqwest::blocking::Client) -> Result<Identity, MmdsError> {
+    let token = client
+        .put(format!("http://{}/latest/api/token", MMDS_ADDRESS))
+        .header("X-metadata-token-ttl-seconds", MMDS_TOKEN_TTL_SECONDS)
+        .send()?
+        .error_for_status()?
+        .text()?;
+
+    let identity = client
+        .get(format!("http://{}/", MMDS_ADDRESS))
+        .header("X-metadata-token", token)
+        .header("Accept", "application/json")
+        .send()?
+        .error_for_status()?
+        .json::<Identity>()?;
+
+    Ok(identity)
+}
+
+// Poll the metadata service until the manager has restored us and handed us an identity.
+// While we're a template the metadata service is empty, so this keeps polling until
+// we're snapshotted.
+pub fn wait_for_identity() -> Result<Identity, MmdsError> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(1))
+        .build()?;
+
+    loop {
+        match fetch_identity(&client) {
+            Ok(identity) => return Ok(identity),
+            Err(e) => log::trace!("No identity yet: {}", e),
+        }
+        thread::sleep(MMDS_POLL_INTERVAL);
+    }
+}
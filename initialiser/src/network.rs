@@ -1,4 +1,4 @@
-use config::{NETWORK_MAGIC_MAC_START, NETWORK_MASK_SHORT};
+use config::{MMDS_ADDRESS, NETWORK_MAGIC_MAC_START, NETWORK_MASK_SHORT};
 use serde::Deserialize;
 
 use std::{fs::write, net::Ipv4Addr, process::Command};
@@ -69,6 +69,29 @@ pub fn setup_network() -> Result<Option<NetworkInterface>, NetworkError> {
         1,
     );
 
+    configure_interface(&magic_address, own_ip, host_ip).map(Some)
+}
+
+// Instances restored from a snapshot have the MAC address of the template,
+// so their addresses are passed in explicitly.
+pub fn reconfigure_network(
+    own_ip: Ipv4Addr,
+    host_ip: Ipv4Addr,
+) -> Result<NetworkInterface, NetworkError> {
+    let magic_address = get_magic_address()?;
+
+    exec(Command::new("ip").args(["addr", "flush", "dev", &magic_address.ifname]))?;
+
+    let interface = configure_interface(&magic_address, own_ip, host_ip)?;
+    setup_mmds_route()?;
+    Ok(interface)
+}
+
+fn configure_interface(
+    magic_address: &NetworkAddress,
+    own_ip: Ipv4Addr,
+    host_ip: Ipv4Addr,
+) -> Result<NetworkInterface, NetworkError> {
     exec(Command::new("ip").args([
         "addr",
         "add",
@@ -81,18 +104,32 @@ pub fn setup_network() -> Result<Option<NetworkInterface>, NetworkError> {
 
     exec(Command::new("ip").args([
         "route",
-        "add",
+        "replace",
         "default",
         "via",
         host_ip.to_string().as_str(),
     ]))?;
 
-    Ok(Some(NetworkInterface {
+    Ok(NetworkInterface {
         ifname: magic_address.ifname.to_string(),
         mac: magic_address.mac.to_string(),
         own_address: own_ip,
         host_address: host_ip,
-    }))
+    })
+}
+
+// The metadata service is only reachable if we route its link-local address to our interface
+pub fn setup_mmds_route() -> Result<(), NetworkError> {
+    let magic_address = get_magic_address()?;
+
+    exec(Command::new("ip").args([
+        "route",
+        "replace",
+        MMDS_ADDRESS,
+        "dev",
+        &magic_address.ifname,
+    ]))?;
+    Ok(())
 }
 
 pub fn setup_dns() -> Result<(), NetworkError> {
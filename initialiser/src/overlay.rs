@@ -0,0 +1,45 @@
+use anyhow::Result;
+use std::env;
+use thiserror::Error;
+use util::{fs, mount, CommandExecutionError};
+
+// The rootfs is attached read-only, so we build the new root on a tmpfs
+// mounted on `/mnt`, which exists in every image.
+const STAGING_PATH: &str = "/mnt";
+const OVERLAY_PATH: &str = "/mnt/overlay";
+const ROOT_PATH: &str = "/mnt/root";
+const OLD_ROOT_PATH: &str = "/mnt/root/rom";
+
+#[derive(Error, Debug)]
+pub enum OverlayError {
+    #[error("IO error: {:?}", self)]
+    Io(#[from] std::io::Error),
+    #[error("Could not mount: {:?}", self)]
+    Mount(#[from] CommandExecutionError),
+}
+
+// Mount a writable overlay on top of the read-only rootfs, and switch to it.
+// The original rootfs stays available on `/rom`.
+pub fn setup_overlay(device: &str) -> Result<(), OverlayError> {
+    mount::mount_tmpfs(STAGING_PATH)?;
+
+    fs::mkdir_p(OVERLAY_PATH)?;
+    mount::mount_ext4(format!("/dev/{}", device), OVERLAY_PATH)?;
+
+    let upper_path = format!("{}/upper", OVERLAY_PATH);
+    let work_path = format!("{}/work", OVERLAY_PATH);
+    fs::mkdir_p(&upper_path)?;
+    fs::mkdir_p(&work_path)?;
+    fs::mkdir_p(ROOT_PATH)?;
+    mount::mount_overlay("/", &upper_path, &work_path, ROOT_PATH)?;
+
+    fs::mkdir_p(OLD_ROOT_PATH)?;
+    mount::pivot_root(ROOT_PATH, OLD_ROOT_PATH)?;
+    env::set_current_dir("/")?;
+
+    // The overlay doesn't carry over the mounts of the old root, make sure
+    // the block devices are available for the cache setup.
+    mount::mount_devtmpfs("/dev")?;
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
-use std::fs::write;
+use config::mmds::Identity;
+use std::fs::{create_dir_all, write};
 use std::os::unix::fs::symlink;
 
 pub const SERVICE_PATH: &str = "/etc/systemd/system/runner.service";
@@ -23,6 +24,31 @@ Environment="GITHUB_RUNNER_LABELS={github_runner_labels}"
 ExecStopPost=+/usr/sbin/reboot
 "#;
 
+// Snapshot templates don't know who they are yet, the restore step waits
+// for an identity and writes the runner's environment file.
+pub const ENVIRONMENT_DIR: &str = "/etc/actions-runner";
+pub const ENVIRONMENT_PATH: &str = "/etc/actions-runner/runner.env";
+// It's ordered after Docker, so the template is only snapshotted once Docker is up. Ordering
+// after a unit the image doesn't have is a no-op.
+pub const SNAPSHOT_SERVICE_TEMPLATE: &str = r#"
+[Unit]
+Description=Actions Runner
+After=network.target docker.service
+
+[Service]
+ExecStartPre=+/sbin/actions-init restore
+ExecStart=/sbin/actions-run
+KillMode=control-group
+KillSignal=SIGTERM
+TimeoutStartSec=infinity
+TimeoutStopSec=5min
+WorkingDirectory=/home/runner
+User=runner
+Restart=never
+EnvironmentFile=/etc/actions-runner/runner.env
+ExecStopPost=+/usr/sbin/reboot
+"#;
+
 pub fn setup_service(
     github_org: &str,
     github_token: &str,
@@ -40,6 +66,29 @@ pub fn setup_service(
     Ok(())
 }
 
+pub fn setup_snapshot_service() -> Result<()> {
+    write(SERVICE_PATH, SNAPSHOT_SERVICE_TEMPLATE)?;
+
+    Ok(())
+}
+
+pub fn write_environment(identity: &Identity) -> Result<()> {
+    let environment = [
+        ("GITHUB_ORG", &identity.github_org),
+        ("GITHUB_TOKEN", &identity.github_token),
+        ("GITHUB_RUNNER_NAME", &identity.github_runner_name),
+        ("GITHUB_RUNNER_LABELS", &identity.github_runner_labels),
+    ]
+    .iter()
+    .map(|(key, value)| format!("{}={}\n", key, value))
+    .collect::<String>();
+
+    create_dir_all(ENVIRONMENT_DIR)?;
+    write(ENVIRONMENT_PATH, environment)?;
+
+    Ok(())
+}
+
 pub fn enable_service() -> Result<()> {
     symlink(
         SERVICE_PATH,
@@ -1,5 +1,6 @@
 use camino::Utf8PathBuf;
-use util::fs;
+pub use config::disk::DiskFormat;
+use util::fs::{self, CloneStrategy, FsUsage};
 
 #[derive(Debug)]
 pub struct Disk {
@@ -9,11 +10,6 @@ pub struct Disk {
     pub format: DiskFormat,
 }
 
-#[derive(Debug)]
-pub enum DiskFormat {
-    Ext4,
-}
-
 impl Disk {
     pub fn new(path: &Utf8PathBuf, name: &str, size: u32, format: DiskFormat) -> Self {
         Self {
@@ -33,29 +29,45 @@ impl Disk {
     }
 
     pub fn filename(&self) -> Utf8PathBuf {
-        match self.format {
-            DiskFormat::Ext4 => Utf8PathBuf::from(format!("{}.ext4", &self.name)),
-        }
+        Utf8PathBuf::from(format!("{}.{}", &self.name, self.format))
     }
 
     pub fn path_with_filename(&self) -> Utf8PathBuf {
         self.path.join(self.filename())
     }
 
+    // Whether the disk exists with the size it's configured with
+    pub fn exists(&self) -> bool {
+        std::fs::metadata(self.path_with_filename())
+            .is_ok_and(|metadata| metadata.len() == self.size_in_megabytes() * 1024 * 1024)
+    }
+
     pub fn setup(&self) -> Result<(), std::io::Error> {
-        match self.format {
-            DiskFormat::Ext4 => self.setup_ext4(),
-        }
+        fs::dd(self.path_with_filename(), self.size_in_megabytes())?;
+        fs::mkfs(self.path_with_filename(), self.format)?;
+        Ok(())
     }
 
     pub fn usage_on_disk(&self) -> Result<u64, std::io::Error> {
         fs::du(self.path_with_filename())
     }
 
-    pub fn setup_ext4(&self) -> Result<(), std::io::Error> {
-        fs::dd(self.path_with_filename(), self.size_in_megabytes())?;
-        fs::mkfs_ext4(self.path_with_filename())?;
-        Ok(())
+    // Replace this disk with a clone of the given disk
+    pub fn clone_from(
+        &self,
+        source: &Disk,
+        strategy: CloneStrategy,
+    ) -> Result<CloneStrategy, std::io::Error> {
+        fs::clone_file(
+            source.path_with_filename(),
+            self.path_with_filename(),
+            strategy,
+        )
+    }
+
+    // Move this disk to the place of the given disk, replacing it
+    pub fn rename_to(&self, target: &Disk) -> Result<(), std::io::Error> {
+        std::fs::rename(self.path_with_filename(), target.path_with_filename())
     }
 
     pub fn destroy(&self) -> Result<(), std::io::Error> {
@@ -63,7 +75,33 @@ impl Disk {
         Ok(())
     }
 
+    // The usage of the filesystem on the disk, which we can only read directly from ext4 images.
+    // Their superblock is stale when the guest didn't shut down cleanly, so they're checked first.
+    // For other formats we fall back to the blocks the image file takes up on the host.
+    pub fn usage(&self) -> Result<FsUsage, std::io::Error> {
+        match self.format {
+            DiskFormat::Ext4 => {
+                fs::e2fsck(self.path_with_filename())?;
+                fs::ext4_usage(self.path_with_filename())
+            }
+            DiskFormat::Xfs | DiskFormat::Btrfs => Ok(FsUsage {
+                used_bytes: self.usage_on_disk()? * 1024,
+                total_bytes: self.size_in_kilobytes() * 1024,
+            }),
+        }
+    }
+
     pub fn usage_pct(&self) -> Result<u8, std::io::Error> {
-        Ok((self.usage_on_disk()? * 100 / self.size_in_kilobytes()) as u8)
+        Ok(self.usage()?.used_pct())
+    }
+
+    // Give the blocks of deleted files back to the host, so the sparse image shrinks. A guest
+    // could have written anything to the disk, so it's not mounted for that.
+    pub fn trim(&self) -> Result<(), std::io::Error> {
+        match self.format {
+            DiskFormat::Ext4 => fs::e2fsck_discard(self.path_with_filename()),
+            // Only finds the blocks that are zeroed, deleted files usually aren't
+            DiskFormat::Xfs | DiskFormat::Btrfs => fs::dig_holes(self.path_with_filename()),
+        }
     }
 }
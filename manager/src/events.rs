@@ -0,0 +1,257 @@
+use crate::metrics::{CACHE_EVICTIONS, CACHE_WIPES, METRICS};
+use camino::{Utf8Path, Utf8PathBuf};
+use config::events::GuestEvent;
+use log::*;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use util::fs::FsUsage;
+
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the guest last told us about itself
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum GuestState {
+    #[default]
+    Starting,
+    Booted,
+    Idle,
+    Busy {
+        job: String,
+    },
+    Finished {
+        result: String,
+    },
+    ShuttingDown,
+}
+
+impl GuestState {
+    fn next(&self, event: GuestEvent) -> Self {
+        match event {
+            GuestEvent::Booted => GuestState::Booted,
+            GuestEvent::Registered => GuestState::Idle,
+            GuestEvent::JobStarted { job } => GuestState::Busy { job },
+            GuestEvent::JobFinished { result } => GuestState::Finished { result },
+            GuestEvent::ShuttingDown => GuestState::ShuttingDown,
+            GuestEvent::CacheUsage { .. }
+            | GuestEvent::CacheEvicted { .. }
+            | GuestEvent::CacheWiped { .. } => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for GuestState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuestState::Starting => write!(f, "starting"),
+            GuestState::Booted => write!(f, "booted"),
+            GuestState::Idle => write!(f, "idle"),
+            GuestState::Busy { job } => write!(f, "busy ({})", job),
+            GuestState::Finished { result } => write!(f, "finished ({})", result),
+            GuestState::ShuttingDown => write!(f, "shutting down"),
+        }
+    }
+}
+
+// Listens on the host side of the vsock device for the events of the guest
+#[derive(Debug, Clone, Default)]
+pub struct GuestEvents {
+    role: String,
+    idx: u8,
+    state: Arc<Mutex<GuestState>>,
+    // The result of the last job of this boot
+    job_result: Arc<Mutex<Option<String>>>,
+    // The usage of the cache disk, as last reported by the guest
+    cache_usage: Arc<Mutex<Option<FsUsage>>>,
+    // Bumped on every boot, so the listener of the previous boot stops
+    generation: Arc<AtomicUsize>,
+}
+
+impl GuestEvents {
+    pub fn new(role: &str, idx: u8) -> Self {
+        Self {
+            role: role.to_string(),
+            idx,
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> GuestState {
+        self.state
+            .lock()
+            .expect("Guest state lock poisoned")
+            .clone()
+    }
+
+    pub fn job_result(&self) -> Option<String> {
+        self.job_result
+            .lock()
+            .expect("Job result lock poisoned")
+            .clone()
+    }
+
+    pub fn cache_usage(&self) -> Option<FsUsage> {
+        *self.cache_usage.lock().expect("Cache usage lock poisoned")
+    }
+
+    pub fn set_cache_usage(&self, usage: Option<FsUsage>) {
+        *self.cache_usage.lock().expect("Cache usage lock poisoned") = usage;
+    }
+
+    pub fn record(&self, event: GuestEvent) {
+        if let GuestEvent::JobFinished { ref result } = event {
+            *self.job_result.lock().expect("Job result lock poisoned") = Some(result.clone());
+        }
+        if let GuestEvent::CacheUsage {
+            used_bytes,
+            total_bytes,
+        } = event
+        {
+            self.set_cache_usage(Some(FsUsage {
+                used_bytes,
+                total_bytes,
+            }));
+        }
+
+        match event {
+            GuestEvent::CacheEvicted {
+                ref name,
+                size_bytes,
+                over_quota,
+            } => {
+                METRICS.inc(CACHE_EVICTIONS, &[("role", &self.role)]);
+                let reason = if over_quota {
+                    "it's over its quota"
+                } else {
+                    "it's the least recently used"
+                };
+                info!(
+                    role = self.role.as_str(), instance_idx = self.idx;
+                    "Guest evicted cache '{}' ({}MB), {}", name, size_bytes / 1024 / 1024, reason
+                );
+            }
+            GuestEvent::CacheWiped { used_pct } => {
+                METRICS.inc(CACHE_WIPES, &[("role", &self.role)]);
+                info!(
+                    role = self.role.as_str(), instance_idx = self.idx;
+                    "Guest wiped its cache, evicting left it {}% full", used_pct
+                );
+            }
+            _ => {}
+        }
+
+        let mut state = self.state.lock().expect("Guest state lock poisoned");
+        let next = state.next(event);
+        if next != *state {
+            info!(role = self.role.as_str(), instance_idx = self.idx; "Guest is {}", next);
+        }
+        *state = next;
+    }
+
+    // Start listening for a new boot, the guest starts out as `Starting`
+    pub fn listen(&self, socket_path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+        let socket_path: Utf8PathBuf = socket_path.as_ref().to_path_buf();
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        *self.state.lock().expect("Guest state lock poisoned") = GuestState::Starting;
+        *self.job_result.lock().expect("Job result lock poisoned") = None;
+        self.set_cache_usage(None);
+
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+        // Firecracker might run as a different user in the jail
+        fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o666))?;
+
+        let events = self.clone();
+        thread::spawn(move || {
+            while events.generation.load(Ordering::SeqCst) == generation {
+                match listener.accept() {
+                    Ok((stream, _)) => events.read(stream),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(e) => {
+                        warn!(
+                            role = events.role.as_str(), instance_idx = events.idx;
+                            "Stopped listening for guest events: {}", e
+                        );
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn read(&self, stream: UnixStream) {
+        if stream.set_nonblocking(false).is_err()
+            || stream.set_read_timeout(Some(READ_TIMEOUT)).is_err()
+        {
+            return;
+        }
+
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            match serde_json::from_str::<GuestEvent>(&line) {
+                Ok(event) => self.record(event),
+                Err(e) => warn!(
+                    role = self.role.as_str(), instance_idx = self.idx;
+                    "Invalid guest event '{}': {}", line, e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Instant;
+
+    #[test]
+    fn test_listen() {
+        let dir: Utf8PathBuf = "/tmp/test_guest_events".into();
+        fs::create_dir_all(&dir).expect("Could not create dir");
+        let socket_path = dir.join("vsock.socket_1025");
+
+        let events = GuestEvents::new("test", 1);
+        events.listen(&socket_path).expect("Could not listen");
+        assert_eq!(events.state(), GuestState::Starting);
+
+        let mut stream = UnixStream::connect(&socket_path).expect("Could not connect");
+        writeln!(stream, r#"{{"event":"job_started","job":"build"}}"#).expect("Could not write");
+        drop(stream);
+
+        let start = Instant::now();
+        while events.state() == GuestState::Starting && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            events.state(),
+            GuestState::Busy {
+                job: "build".to_string()
+            }
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_job_result() {
+        let events = GuestEvents::new("test", 1);
+        assert_eq!(events.job_result(), None);
+
+        events.record(GuestEvent::JobFinished {
+            result: "Succeeded".to_string(),
+        });
+        events.record(GuestEvent::ShuttingDown);
+
+        assert_eq!(events.state(), GuestState::ShuttingDown);
+        assert_eq!(events.job_result(), Some("Succeeded".to_string()));
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use config::firecracker::{
+    InstanceAction, Logger, MemBackend, Metrics, NetworkOverride, SnapshotCreate, SnapshotLoad,
+    VmState,
+};
+use serde::Serialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use util::{exec_spawn, inner};
+
+pub const API_SOCKET: &str = "firecracker.socket";
+const API_SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A client for the Firecracker API, which listens on a unix socket
+#[derive(Debug)]
+pub struct FirecrackerApi {
+    socket: Utf8PathBuf,
+}
+
+impl FirecrackerApi {
+    pub fn new(socket: impl AsRef<Utf8Path>) -> Self {
+        Self {
+            socket: socket.as_ref().to_path_buf(),
+        }
+    }
+
+    // Firecracker creates the socket shortly after it started
+    pub fn wait_for_socket(&self) -> Result<()> {
+        let start = Instant::now();
+        while !self.socket.exists() {
+            if start.elapsed() > API_SOCKET_TIMEOUT {
+                return Err(anyhow!("API socket '{}' did not show up", self.socket));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.request(
+            "PATCH",
+            "/vm",
+            &VmState {
+                state: "Paused".to_string(),
+            },
+        )
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.request(
+            "PATCH",
+            "/vm",
+            &VmState {
+                state: "Resumed".to_string(),
+            },
+        )
+    }
+
+    // Asks the guest to shut down, Firecracker exits once the guest rebooted
+    pub fn send_ctrl_alt_del(&self) -> Result<()> {
+        self.request(
+            "PUT",
+            "/actions",
+            &InstanceAction {
+                action_type: "SendCtrlAltDel".to_string(),
+            },
+        )
+    }
+
+    pub fn create_snapshot(
+        &self,
+        snapshot_path: &Utf8Path,
+        mem_file_path: &Utf8Path,
+    ) -> Result<()> {
+        self.request(
+            "PUT",
+            "/snapshot/create",
+            &SnapshotCreate {
+                snapshot_type: "Full".to_string(),
+                snapshot_path: snapshot_path.to_path_buf(),
+                mem_file_path: mem_file_path.to_path_buf(),
+            },
+        )
+    }
+
+    pub fn load_snapshot(
+        &self,
+        snapshot_path: &Utf8Path,
+        mem_file_path: &Utf8Path,
+        tap_name: &str,
+    ) -> Result<()> {
+        self.request(
+            "PUT",
+            "/snapshot/load",
+            &SnapshotLoad {
+                snapshot_path: snapshot_path.to_path_buf(),
+                mem_backend: MemBackend {
+                    backend_type: "File".to_string(),
+                    backend_path: mem_file_path.to_path_buf(),
+                },
+                resume_vm: false,
+                network_overrides: vec![NetworkOverride {
+                    iface_id: "eth0".to_string(),
+                    host_dev_name: tap_name.to_string(),
+                }],
+            },
+        )
+    }
+
+    pub fn put_logger(&self, logger: &Logger) -> Result<()> {
+        self.request("PUT", "/logger", logger)
+    }
+
+    pub fn put_metrics(&self, metrics: &Metrics) -> Result<()> {
+        self.request("PUT", "/metrics", metrics)
+    }
+
+    pub fn put_mmds(&self, data: &impl Serialize) -> Result<()> {
+        self.request("PUT", "/mmds", data)
+    }
+
+    // The body goes in on stdin, it can hold secrets that shouldn't end up in the process list or
+    // in the error when the request fails
+    fn request(&self, method: &str, path: &str, body: &impl Serialize) -> Result<()> {
+        let body = serde_json::to_vec(body)?;
+        let mut command = Command::new("curl");
+        command
+            .args([
+                "--silent",
+                "--show-error",
+                "--fail-with-body",
+                "--unix-socket",
+                self.socket.as_str(),
+                "-X",
+                method,
+                "-H",
+                "Content-Type: application/json",
+                "--data-binary",
+                "@-",
+                &format!("http://localhost{}", path),
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = exec_spawn(&mut command)?;
+        let written = child
+            .stdin
+            .take()
+            .map_or(Ok(()), |mut stdin| stdin.write_all(&body));
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(inner::output_to_exec_error(&command, &output).into());
+        }
+        written?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::{mock_inner, MTX};
+
+    #[test]
+    fn test_request_body_on_stdin() {
+        let _m = MTX.lock();
+        let ctx = mock_inner::internal_exec_spawn_context();
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c)
+                    == "curl --silent --show-error --fail-with-body --unix-socket /tmp/firecracker.socket \
+                        -X PUT -H Content-Type: application/json --data-binary @- http://localhost/mmds"
+            })
+            .returning(|_| {
+                Ok(Command::new("cat")
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .expect("Could not spawn cat"))
+            });
+
+        let api = FirecrackerApi::new("/tmp/firecracker.socket");
+        api.put_mmds(&serde_json::json!({ "github_token": "secret" }))
+            .expect("Request failed");
+        ctx.checkpoint();
+    }
+}
@@ -1,20 +1,59 @@
 use crate::{
     disk::{Disk, DiskFormat},
+    events::{GuestEvents, GuestState},
+    firecracker::{FirecrackerApi, API_SOCKET},
+    jail::{Jail, SHARED_DRIVE_PREFIX},
+    logs::{BootLogs, CONSOLE_LOG, FIRECRACKER_LOG, METRICS_LOG},
+    metrics::{
+        CACHE_PROMOTIONS, CACHE_USAGE_PERCENT, CACHE_WIPES, INSTANCE_RUN_SECONDS, METRICS,
+        ROOTFS_COPY_SECONDS,
+    },
     network::NetworkAllocation,
+    snapshot::Snapshot,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use camino::Utf8PathBuf;
 use config::{
-    firecracker::{BootSource, Drive, FirecrackerConfig, MachineConfig, NetworkInterface},
-    manager::Role,
-    DEFAULT_BOOT_ARGS,
+    firecracker::{
+        BootSource, Drive, FirecrackerConfig, Logger, MachineConfig, Metrics, MmdsConfig,
+        NetworkInterface, Vsock,
+    },
+    manager::{CachePolicy, ExtraDrive, JailerConfig, Role},
+    mmds::Identity,
+    DEFAULT_BOOT_ARGS, GUEST_EVENTS_PORT, GUEST_READY_MARKER, VSOCK_GUEST_CID,
 };
 use github::GitHub;
 use log::*;
 use rand::distributions::{Alphanumeric, DistString};
 use serde_json;
-use std::{fs, process::Command};
-use util::fs::{copy_sparse, rm_rf};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use util::fs::{clone_file, rm_rf, CloneStrategy};
+
+// Firecracker attaches the drives in order, so the overlay (third drive) ends up on `vdc`
+const OVERLAY_DEVICE: &str = "vdc";
+// The seed cache is attached after the overlay, if the role has one
+const SEED_CACHE_DEVICE: &str = "vdd";
+const SEED_CACHE_FILENAME: &str = "seed-cache.img";
+// The extra drives of the role are attached after that, starting at the fourth drive
+const EXTRA_DRIVES_START: usize = 3;
+// The guest names drives `vda` to `vdz`
+const MAX_DRIVES: usize = 26;
+const GUEST_READY_TIMEOUT: Duration = Duration::from_secs(600);
+const LOG_FIFO: &str = "log.fifo";
+const METRICS_FIFO: &str = "metrics.fifo";
+const VSOCK_SOCKET: &str = "vsock.socket";
+// How long we give the guest to shut down before we kill it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+// How often we ask GitHub if an idle runner picked up a job
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// The result the runner reports for a successful job
+const JOB_SUCCEEDED: &str = "Succeeded";
 
 pub enum InstanceState {
     NotStarted,
@@ -23,6 +62,40 @@ pub enum InstanceState {
     Errorred,
 }
 
+impl InstanceState {
+    pub const ALL: [InstanceState; 4] = [
+        InstanceState::NotStarted,
+        InstanceState::Running,
+        InstanceState::NotRunning,
+        InstanceState::Errorred,
+    ];
+}
+
+impl fmt::Display for InstanceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstanceState::NotStarted => write!(f, "not_started"),
+            InstanceState::Running => write!(f, "running"),
+            InstanceState::NotRunning => write!(f, "not_running"),
+            InstanceState::Errorred => write!(f, "errored"),
+        }
+    }
+}
+
+// Whether all drives of a role fit in `vda` to `vdz`
+pub fn check_drives(role: &Role) -> Result<()> {
+    let drives = EXTRA_DRIVES_START + role.seed_cache.is_some() as usize + role.extra_drives.len();
+    if drives > MAX_DRIVES {
+        return Err(anyhow!(
+            "Role '{}' has {} drives, at most {} fit in the guest",
+            role.name,
+            drives,
+            MAX_DRIVES
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Instance {
     network_allocation: NetworkAllocation,
@@ -34,11 +107,35 @@ pub struct Instance {
     memory_size: u32,
     cache_paths: Vec<Utf8PathBuf>,
     cache: Disk,
+    cache_template: Option<Disk>,
+    cache_policy: CachePolicy,
+    // Shared by all instances of the role, when the cache policy is `promote`
+    cache_baseline: Option<Disk>,
+    seed_cache: Option<Utf8PathBuf>,
+    extra_drives: Vec<ExtraDrive>,
+    cache_trim: bool,
+    cache_low_water_pct: u8,
+    cache_quotas: HashMap<String, u32>,
+    overlay: Disk,
+    overlay_template: Disk,
+    clone_strategy: CloneStrategy,
+    snapshot: Option<Snapshot>,
+    jail: Option<Jail>,
+    api: FirecrackerApi,
+    logs: BootLogs,
+    events: GuestEvents,
     max_cache_pct: u8,
     idx: u8,
     role: String,
     github: GitHub,
     labels: Vec<String>,
+    max_lifetime: Option<Duration>,
+    idle_registration_timeout: Option<Duration>,
+    runner_name: String,
+    started_at: Option<Instant>,
+    shutdown_at: Option<Instant>,
+    picked_up_job: bool,
+    last_idle_check: Option<Instant>,
     child: Option<std::process::Child>,
 }
 
@@ -49,11 +146,70 @@ impl Instance {
         work_dir: &Utf8PathBuf,
         role: &Role,
         idx: u8,
+        clone_strategy: CloneStrategy,
+        jailer: Option<&JailerConfig>,
     ) -> Self {
         let instance_dir: Utf8PathBuf = work_dir.join(role.slug()).join(format!("{}", idx));
-        let cache = Disk::new(&instance_dir, "cache", role.cache_size, DiskFormat::Ext4);
+        let cache = Disk::new(&instance_dir, "cache", role.cache_size, role.cache_format);
+        let cache_template = role.cache_template.then(|| {
+            Disk::new(
+                &instance_dir,
+                "cache-template",
+                role.cache_size,
+                role.cache_format,
+            )
+        });
+        let cache_baseline = (role.cache_policy == CachePolicy::Promote).then(|| {
+            Disk::new(
+                &work_dir.join(role.slug()),
+                "cache-baseline",
+                role.cache_size,
+                role.cache_format,
+            )
+        });
+        let overlay = Disk::new(
+            &instance_dir,
+            "overlay",
+            role.overlay_size,
+            DiskFormat::Ext4,
+        );
+        let overlay_template = Disk::new(
+            &instance_dir,
+            "overlay-template",
+            role.overlay_size,
+            DiskFormat::Ext4,
+        );
+        let snapshot = role.snapshot.then(|| {
+            Snapshot::new(
+                work_dir.join(role.slug()).join("snapshot"),
+                role.overlay_size,
+            )
+        });
+        let jail = jailer.map(|jailer| {
+            Jail::new(
+                jailer,
+                work_dir,
+                &format!("{}-{}", role.slug(), idx),
+                idx,
+                &format!("fc{}", idx),
+            )
+        });
+        let network_allocation = match (&jail, jailer) {
+            (Some(jail), Some(jailer)) => network_allocation.with_namespace(
+                &jail.netns,
+                jail.uid,
+                jail.gid,
+                jailer.veth_network,
+            ),
+            _ => network_allocation,
+        };
+        let logs = BootLogs::new(instance_dir.join("logs"), role.boot_logs);
+        let api = match jail {
+            Some(ref jail) => FirecrackerApi::new(jail.root().join(API_SOCKET)),
+            None => FirecrackerApi::new(instance_dir.join(API_SOCKET)),
+        };
 
-        Self {
+        let mut instance = Self {
             network_allocation,
             work_dir: instance_dir.clone(),
             kernel_image: role.kernel_image.clone(),
@@ -65,15 +221,56 @@ impl Instance {
             role: role.slug(),
             max_cache_pct: role.max_cache_pct,
             labels: role.labels.clone(),
+            max_lifetime: role.max_lifetime.map(Duration::from_secs),
+            idle_registration_timeout: role.idle_registration_timeout.map(Duration::from_secs),
+            runner_name: String::new(),
+            started_at: None,
+            shutdown_at: None,
+            picked_up_job: false,
+            last_idle_check: None,
             github,
             cache,
+            cache_template,
+            cache_policy: role.cache_policy,
+            cache_baseline,
+            seed_cache: role.seed_cache.clone(),
+            extra_drives: role.extra_drives.clone(),
+            cache_trim: role.cache_trim,
+            cache_low_water_pct: role.cache_low_water_pct,
+            cache_quotas: role.cache_quotas.clone(),
+            overlay,
+            overlay_template,
+            clone_strategy,
+            snapshot,
+            jail,
+            api,
+            logs,
+            events: GuestEvents::new(&role.slug(), idx),
             idx,
             child: None,
+        };
+        instance.runner_name = instance.name();
+        instance
+    }
+
+    // The directory Firecracker runs in, inside the jail if we have one
+    pub fn run_dir(&self) -> Utf8PathBuf {
+        match self.jail {
+            Some(ref jail) => jail.root(),
+            None => self.work_dir.clone(),
         }
     }
 
-    pub fn log_prefix(&self) -> String {
-        format!("[{} {}]", self.role, self.idx)
+    pub fn role(&self) -> &str {
+        &self.role
+    }
+
+    pub fn idx(&self) -> u8 {
+        self.idx
+    }
+
+    pub fn runner_name(&self) -> &str {
+        &self.runner_name
     }
 
     pub fn name(&self) -> String {
@@ -88,52 +285,114 @@ impl Instance {
     pub fn setup(&mut self) -> Result<()> {
         info!("Running instance with: {:?}", self);
 
-        debug!(
-            "{} Creating work dir: '{}'",
-            self.log_prefix(),
-            self.work_dir
-        );
+        instance_log!(debug, self, "Creating work dir: '{}'", self.work_dir);
         fs::create_dir_all(&self.work_dir)?;
 
-        debug!(
-            "{} Setup network with tap: '{}', host address: '{}'",
-            self.log_prefix(),
+        instance_log!(
+            debug,
+            self,
+            "Setup network with tap: '{}', host address: '{}'",
             self.network_allocation.tap_name,
-            self.network_allocation.host_ip,
+            self.network_allocation.host_ip
         );
         self.network_allocation.setup()?;
 
-        debug!(
-            "{} Initialize shared cache on path: '{}' (size: {}GB)",
-            self.log_prefix(),
-            self.cache.path_with_filename(),
-            self.cache.size,
+        if let Some(ref cache_template) = self.cache_template {
+            instance_log!(
+                debug,
+                self,
+                "Initialize cache template on path: '{}' (size: {}GB)",
+                cache_template.path_with_filename(),
+                cache_template.size
+            );
+            cache_template.destroy()?;
+            cache_template.setup()?;
+        }
+
+        if self.cache_policy == CachePolicy::Persistent && self.cache.exists() {
+            instance_log!(
+                info,
+                self,
+                "Keeping cache from a previous run on path: '{}'",
+                self.cache.path_with_filename()
+            );
+        } else {
+            instance_log!(
+                debug,
+                self,
+                "Initialize shared cache on path: '{}' (size: {}GB, format: {})",
+                self.cache.path_with_filename(),
+                self.cache.size,
+                self.cache.format
+            );
+            self.recreate_cache()?;
+        }
+
+        instance_log!(
+            debug,
+            self,
+            "Initialize overlay template on path: '{}' (size: {}GB)",
+            self.overlay_template.path_with_filename(),
+            self.overlay_template.size
         );
-        self.cache.setup()?;
+        self.overlay_template.destroy()?;
+        self.overlay_template.setup()?;
 
         Ok(())
     }
 
-    pub fn boot_args(&self) -> Result<String> {
+    pub fn cache_paths(&self) -> Option<String> {
+        if self.cache_paths.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.cache_paths
+                .iter()
+                .map(|cp| cp.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+
+    // The quotas of the cache roots, for the guest to evict them by
+    fn cache_quotas(&self) -> Option<String> {
+        if self.cache_quotas.is_empty() {
+            return None;
+        }
+
+        let mut quotas = self
+            .cache_quotas
+            .iter()
+            .map(|(name, quota)| format!("{}:{}", name, quota))
+            .collect::<Vec<String>>();
+        quotas.sort();
+        Some(quotas.join(","))
+    }
+
+    fn base_boot_args(&self) -> Vec<String> {
         let mut boot_args = vec![DEFAULT_BOOT_ARGS.to_string()];
+        boot_args.push(format!("overlay_root={}", OVERLAY_DEVICE));
 
-        // Add GitHub token
-        boot_args.push(format!(
-            "github_token={}",
-            &self.github.registration_token()?
-        ));
-        boot_args.push(format!("github_org={}", &self.github.org));
+        // Output to the serial console, so we can capture it
+        boot_args.push("console=ttyS0".to_string());
 
         // Add cache paths
-        if !self.cache_paths.is_empty() {
-            boot_args.push(format!(
-                "cache_paths=\"{}\"",
-                self.cache_paths
-                    .iter()
-                    .map(|cp| cp.to_string())
-                    .collect::<Vec<String>>()
-                    .join(",")
-            ));
+        if let Some(cache_paths) = self.cache_paths() {
+            boot_args.push(format!("cache_paths=\"{}\"", cache_paths));
+            boot_args.push(format!("cache_format={}", self.cache.format));
+            boot_args.push(format!("cache_max_pct={}", self.max_cache_pct));
+            boot_args.push(format!("cache_low_water_pct={}", self.cache_low_water_pct));
+            if let Some(cache_quotas) = self.cache_quotas() {
+                boot_args.push(format!("cache_quotas=\"{}\"", cache_quotas));
+            }
+            if let Some(seed_cache) = self.seed_cache_device() {
+                boot_args.push(format!("seed_cache={}", seed_cache));
+            }
+        }
+
+        if let Some(extra_drives) = self.extra_drive_mounts() {
+            boot_args.push(format!("extra_drives=\"{}\"", extra_drives));
         }
 
         // Add overridden boot args
@@ -141,41 +400,192 @@ impl Instance {
             boot_args.push(cmdline.to_string());
         }
 
-        boot_args.push(format!("github_runner_name={}", self.name()));
+        boot_args
+    }
+
+    pub fn boot_args(&self) -> Result<String> {
+        let mut boot_args = self.base_boot_args();
+
+        // Add GitHub token
+        boot_args.push(format!(
+            "github_token={}",
+            &METRICS.github("registration_token", || self.github.registration_token())?
+        ));
+        boot_args.push(format!("github_org={}", &self.github.org));
+
+        boot_args.push(format!("github_runner_name={}", self.runner_name));
         boot_args.push(format!("github_runner_labels={}", self.labels()));
 
         Ok(boot_args.join(" "))
     }
 
+    // A snapshot template boots without a GitHub identity, it gets one after it's restored.
+    pub fn template_boot_args(&self) -> String {
+        let mut boot_args = self.base_boot_args();
+        boot_args.push("snapshot_template=1".to_string());
+        boot_args.join(" ")
+    }
+
+    pub fn identity(&self) -> Result<Identity> {
+        Ok(Identity {
+            hostname: self.runner_name.clone(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+            own_address: self.network_allocation.client_ip,
+            host_address: self.network_allocation.host_ip,
+            cache_paths: self.cache_paths(),
+            cache_format: self.cache.format,
+            seed_cache: self.seed_cache_device().map(str::to_string),
+            cache_max_pct: Some(self.max_cache_pct),
+            cache_low_water_pct: Some(self.cache_low_water_pct),
+            cache_quotas: self.cache_quotas(),
+            extra_drives: self.extra_drive_mounts(),
+            github_org: self.github.org.clone(),
+            github_token: METRICS
+                .github("registration_token", || self.github.registration_token())?,
+            github_runner_name: self.runner_name.clone(),
+            github_runner_labels: self.labels(),
+        })
+    }
+
     pub fn labels(&self) -> String {
         let mut labels = self.labels.clone();
         labels.push(self.role.to_string());
         labels.join(",")
     }
 
+    // The device the guest finds the seed cache on, we only need it when there's a cache
+    fn seed_cache_device(&self) -> Option<&str> {
+        match (&self.seed_cache, self.cache_paths.is_empty()) {
+            (Some(_), false) => Some(SEED_CACHE_DEVICE),
+            _ => None,
+        }
+    }
+
+    // The name of an extra drive in the work dir or jail. Writable drives are cloned for
+    // every boot, read-only drives are shared.
+    fn extra_drive_filename(&self, n: usize, extra_drive: &ExtraDrive) -> Utf8PathBuf {
+        if extra_drive.read_only {
+            format!("{}{}.img", SHARED_DRIVE_PREFIX, n).into()
+        } else {
+            format!("drive-{}.img", n).into()
+        }
+    }
+
+    // The device and mount point of every extra drive, e.g. `vde:/fixtures:ro`. `check_drives`
+    // makes sure they all have a name.
+    fn extra_drive_mounts(&self) -> Option<String> {
+        if self.extra_drives.is_empty() {
+            return None;
+        }
+
+        let start = EXTRA_DRIVES_START + self.seed_cache.is_some() as usize;
+        Some(
+            self.extra_drives
+                .iter()
+                .enumerate()
+                .map(|(n, extra_drive)| {
+                    format!(
+                        "vd{}:{}:{}",
+                        (b'a' + (start + n) as u8) as char,
+                        extra_drive.mount_point,
+                        if extra_drive.read_only { "ro" } else { "rw" }
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(","),
+        )
+    }
+
+    // Give every boot its own copy of the writable extra drives
+    fn recreate_extra_drives(&self) -> Result<()> {
+        for (n, extra_drive) in self.extra_drives.iter().enumerate() {
+            if extra_drive.read_only {
+                continue;
+            }
+
+            let path = self
+                .work_dir
+                .join(self.extra_drive_filename(n, extra_drive));
+            instance_log!(
+                debug,
+                self,
+                "Clone extra drive: '{}' to: '{}'",
+                extra_drive.path,
+                path
+            );
+            rm_rf(&path)?;
+            clone_file(&extra_drive.path, &path, self.clone_strategy)?;
+        }
+        Ok(())
+    }
+
     pub fn config(&self) -> Result<FirecrackerConfig> {
+        Ok(self.config_with_boot_args(self.boot_args()?))
+    }
+
+    pub fn config_with_boot_args(&self, boot_args: String) -> FirecrackerConfig {
+        // The jail only contains the files we linked into it
+        let (kernel_image_path, rootfs_path) = match self.jail {
+            Some(_) => ("vmlinux".into(), "rootfs.ext4".into()),
+            None => (self.kernel_image.clone(), self.rootfs_image.clone()),
+        };
+
         let boot_source = BootSource {
-            kernel_image_path: self.kernel_image.to_string(),
-            boot_args: self.boot_args()?,
+            kernel_image_path: kernel_image_path.to_string(),
+            boot_args,
         };
 
-        let drives = vec![
+        let mut drives = vec![
             Drive {
                 drive_id: "rootfs".to_string(),
-                path_on_host: self.work_dir.join("rootfs.ext4"),
+                path_on_host: rootfs_path,
                 is_root_device: true,
-                is_read_only: false,
+                is_read_only: true,
                 cache_type: None,
             },
             Drive {
                 drive_id: "cache".to_string(),
-                path_on_host: self.cache.path_with_filename(),
+                path_on_host: self.cache.filename(),
+                is_root_device: false,
+                is_read_only: false,
+                cache_type: None,
+            },
+            Drive {
+                drive_id: "overlay".to_string(),
+                path_on_host: self.overlay.filename(),
                 is_root_device: false,
                 is_read_only: false,
                 cache_type: None,
             },
         ];
 
+        if let Some(ref seed_cache) = self.seed_cache {
+            drives.push(Drive {
+                drive_id: "seed_cache".to_string(),
+                path_on_host: match self.jail {
+                    Some(_) => SEED_CACHE_FILENAME.into(),
+                    None => seed_cache.clone(),
+                },
+                is_root_device: false,
+                is_read_only: true,
+                cache_type: None,
+            });
+        }
+
+        for (n, extra_drive) in self.extra_drives.iter().enumerate() {
+            let filename = self.extra_drive_filename(n, extra_drive);
+            drives.push(Drive {
+                drive_id: format!("extra_drive_{}", n),
+                path_on_host: match (&self.jail, extra_drive.read_only) {
+                    (None, true) => extra_drive.path.clone(),
+                    _ => filename,
+                },
+                is_root_device: false,
+                is_read_only: extra_drive.read_only,
+                cache_type: extra_drive.cache_type.clone(),
+            });
+        }
+
         let network_interfaces = vec![NetworkInterface {
             iface_id: "eth0".to_string(),
             guest_mac: self.network_allocation.guest_mac.clone(),
@@ -187,64 +597,302 @@ impl Instance {
             mem_size_mib: self.memory_size * 1024,
         };
 
-        Ok(FirecrackerConfig {
+        // Restored snapshots get their identity through the metadata service
+        let mmds_config = self.snapshot.as_ref().map(|_| MmdsConfig {
+            version: "V2".to_string(),
+            network_interfaces: vec!["eth0".to_string()],
+        });
+
+        FirecrackerConfig {
             boot_source,
             drives,
             network_interfaces,
             machine_config,
-        })
+            mmds_config,
+            vsock: Some(Vsock {
+                guest_cid: VSOCK_GUEST_CID,
+                uds_path: VSOCK_SOCKET.into(),
+            }),
+            logger: Some(self.logger()),
+            metrics: Some(self.metrics()),
+        }
     }
 
-    pub fn setup_run(&mut self) -> Result<()> {
-        debug!(
-            "{} Copy rootfs from: '{}'to '{}'",
-            self.log_prefix(),
-            self.rootfs_image,
-            self.work_dir.join("rootfs.ext4"),
-        );
-        let _ = rm_rf(self.work_dir.join("rootfs.ext4"));
-        copy_sparse(&self.rootfs_image, self.work_dir.join("rootfs.ext4"))?;
+    fn logger(&self) -> Logger {
+        Logger {
+            log_path: LOG_FIFO.into(),
+            level: "Info".to_string(),
+            show_level: true,
+            show_log_origin: false,
+        }
+    }
 
-        self.try_clear_cache()?;
+    fn metrics(&self) -> Metrics {
+        Metrics {
+            metrics_path: METRICS_FIFO.into(),
+        }
+    }
 
-        debug!(
-            "{} Generate config: '{}'",
-            self.log_prefix(),
-            self.work_dir.join("config.json")
+    // Start the logs for a new boot, and capture Firecracker's log and metrics
+    fn start_logs(&self) -> Result<()> {
+        self.logs.rotate()?;
+        self.logs
+            .capture_fifo(self.run_dir().join(LOG_FIFO), FIRECRACKER_LOG)?;
+        self.logs
+            .capture_fifo(self.run_dir().join(METRICS_FIFO), METRICS_LOG)?;
+        Ok(())
+    }
+
+    // Listen for the events the guest reports over vsock
+    fn start_events(&self) -> Result<()> {
+        self.events.listen(
+            self.run_dir()
+                .join(format!("{}_{}", VSOCK_SOCKET, GUEST_EVENTS_PORT)),
+        )?;
+        Ok(())
+    }
+
+    pub fn guest_state(&self) -> GuestState {
+        self.events.state()
+    }
+
+    // The last lines of the serial console and Firecracker's log of the current boot
+    pub fn log_tail(&self, count: usize) -> Vec<String> {
+        [CONSOLE_LOG, FIRECRACKER_LOG]
+            .iter()
+            .flat_map(|name| {
+                self.logs
+                    .tail(name, count)
+                    .into_iter()
+                    .map(move |line| format!("{}: {}", name, line))
+            })
+            .collect()
+    }
+
+    fn recreate_overlay(&self, source: &Disk) -> Result<()> {
+        instance_log!(
+            debug,
+            self,
+            "Recreate overlay on: '{}' from: '{}' (size: {}GB)",
+            self.overlay.path_with_filename(),
+            source.path_with_filename(),
+            self.overlay.size
+        );
+        self.overlay.destroy()?;
+        let start = Instant::now();
+        let strategy = self.overlay.clone_from(source, self.clone_strategy)?;
+        METRICS.observe(
+            ROOTFS_COPY_SECONDS,
+            &[("role", &self.role)],
+            start.elapsed().as_secs_f64(),
+        );
+        instance_log!(
+            info,
+            self,
+            "Cloned overlay with {} in {}ms",
+            strategy,
+            start.elapsed().as_millis()
+        );
+        Ok(())
+    }
+
+    fn write_config(&self, config: &FirecrackerConfig) -> Result<()> {
+        instance_log!(
+            debug,
+            self,
+            "Generate config: '{}'",
+            self.run_dir().join("config.json")
         );
 
         fs::write(
-            self.work_dir.join("config.json"),
-            serde_json::to_string(&self.config()?)?,
+            self.run_dir().join("config.json"),
+            serde_json::to_string(config)?,
         )?;
         Ok(())
     }
 
+    // Recreate the jail, and link the images and disks into it
+    fn setup_jail(&self) -> Result<()> {
+        let jail = match self.jail {
+            Some(ref jail) => jail,
+            None => return Ok(()),
+        };
+
+        instance_log!(debug, self, "Setup jail in: '{}'", jail.root());
+        jail.setup()?;
+        jail.link_file(&self.kernel_image, "vmlinux")?;
+        jail.link_file(&self.rootfs_image, "rootfs.ext4")?;
+        jail.link_file(
+            &self.cache.path_with_filename(),
+            self.cache.filename().as_str(),
+        )?;
+        jail.link_file(
+            &self.overlay.path_with_filename(),
+            self.overlay.filename().as_str(),
+        )?;
+        if let Some(ref seed_cache) = self.seed_cache {
+            jail.link_file(seed_cache, SEED_CACHE_FILENAME)?;
+        }
+        for (n, extra_drive) in self.extra_drives.iter().enumerate() {
+            let filename = self.extra_drive_filename(n, extra_drive);
+            let from = match extra_drive.read_only {
+                true => extra_drive.path.clone(),
+                false => self.work_dir.join(&filename),
+            };
+            jail.link_file(&from, filename.as_str())?;
+        }
+        Ok(())
+    }
+
+    pub fn setup_run(&mut self) -> Result<()> {
+        self.runner_name = self.name();
+        self.recreate_overlay(&self.overlay_template)?;
+        self.prepare_cache()?;
+        self.recreate_extra_drives()?;
+        self.setup_jail()?;
+        self.write_config(&self.config()?)?;
+        Ok(())
+    }
+
     pub fn cleanup(&self) -> Result<()> {
-        let _ = rm_rf(&self.work_dir);
+        if let Some(ref jail) = self.jail {
+            let _ = jail.cleanup();
+        }
+
+        // A persistent cache is picked up again when the manager is restarted
+        if self.cache_policy != CachePolicy::Persistent || !self.work_dir.exists() {
+            let _ = rm_rf(&self.work_dir);
+            return Ok(());
+        }
+        for entry in self.work_dir.read_dir_utf8()? {
+            let entry = entry?;
+            if entry.file_name() != self.cache.filename() {
+                let _ = rm_rf(entry.path());
+            }
+        }
         Ok(())
     }
 
     pub fn reset(&mut self) {
         self.child = None;
+        self.started_at = None;
+        self.shutdown_at = None;
+        self.picked_up_job = false;
+        self.last_idle_check = None;
+    }
+
+    // Get the cache ready for the next boot, according to the cache policy of the role
+    fn prepare_cache(&self) -> Result<()> {
+        match self.cache_policy {
+            CachePolicy::Persistent => self.try_clear_cache(),
+            CachePolicy::Ephemeral => {
+                instance_log!(debug, self, "Recreate ephemeral cache");
+                self.recreate_cache()
+            }
+            CachePolicy::Promote => {
+                // The cache stays if its job succeeded, it's the new baseline after all
+                if self.events.job_result().as_deref() == Some(JOB_SUCCEEDED) {
+                    self.try_clear_cache()?;
+                    self.promote_cache()
+                } else {
+                    instance_log!(debug, self, "Recreate cache from baseline");
+                    self.recreate_cache()
+                }
+            }
+        }
+    }
+
+    // Make the cache of the instance the baseline of the role
+    fn promote_cache(&self) -> Result<()> {
+        let cache_baseline = match self.cache_baseline {
+            Some(ref cache_baseline) => cache_baseline,
+            None => return Ok(()),
+        };
+
+        // Clone next to the baseline first, so other instances never see half a baseline
+        let promoted = Disk::new(
+            &cache_baseline.path,
+            &format!("{}-{}", cache_baseline.name, self.idx),
+            cache_baseline.size,
+            cache_baseline.format,
+        );
+        promoted.destroy()?;
+        promoted.clone_from(&self.cache, self.clone_strategy)?;
+        promoted.rename_to(cache_baseline)?;
+
+        METRICS.inc(CACHE_PROMOTIONS, &[("role", &self.role)]);
+        instance_log!(
+            info,
+            self,
+            "Promoted cache to baseline: '{}'",
+            cache_baseline.path_with_filename()
+        );
+        Ok(())
     }
 
-    pub fn try_clear_cache(&mut self) -> Result<()> {
-        let usage_pct = self.cache.usage_pct()?;
-        if usage_pct > self.max_cache_pct {
-            info!(
+    pub fn try_clear_cache(&self) -> Result<()> {
+        // The guest knows best, it reports the usage when it's done. We only look at the disk
+        // ourselves when it didn't, e.g. because it crashed.
+        let usage_pct = match self.events.cache_usage() {
+            Some(usage) => usage.used_pct(),
+            None => match self.cache.usage_pct() {
+                Ok(usage_pct) => usage_pct,
+                Err(e) => {
+                    METRICS.inc(CACHE_WIPES, &[("role", &self.role)]);
+                    instance_log!(warn, self, "Could not check cache, clearing it: {}", e);
+                    return self.recreate_cache();
+                }
+            },
+        };
+        METRICS.set(
+            CACHE_USAGE_PERCENT,
+            &[("role", &self.role), ("instance", &self.idx.to_string())],
+            usage_pct as f64,
+        );
+
+        // The guest evicts cache roots itself when it mounts the cache, and wipes it if that's
+        // not enough. We only wipe caches it doesn't mount.
+        if usage_pct > self.max_cache_pct && self.cache_paths.is_empty() {
+            METRICS.inc(CACHE_WIPES, &[("role", &self.role)]);
+            instance_log!(
+                info,
+                self,
                 "Cache disk is over {}% ({}%), clearing cache",
-                self.max_cache_pct, usage_pct
+                self.max_cache_pct,
+                usage_pct
+            );
+            self.recreate_cache()?;
+        } else if self.cache_trim {
+            instance_log!(
+                debug,
+                self,
+                "Trim cache disk: '{}'",
+                self.cache.path_with_filename()
             );
+            self.cache.trim()?;
+        }
+        Ok(())
+    }
 
-            self.cache.destroy()?;
-            self.cache.setup()?;
+    // Start from the role's baseline if there is one, otherwise with an empty cache
+    // that's cloned from the template if we have one.
+    fn recreate_cache(&self) -> Result<()> {
+        self.events.set_cache_usage(None);
+        self.cache.destroy()?;
+        match (&self.cache_baseline, &self.cache_template) {
+            (Some(cache_baseline), _) if cache_baseline.exists() => {
+                self.cache.clone_from(cache_baseline, self.clone_strategy)?;
+            }
+            (_, Some(cache_template)) => {
+                self.cache.clone_from(cache_template, self.clone_strategy)?;
+            }
+            _ => self.cache.setup()?,
         }
         Ok(())
     }
 
     pub fn stop(&mut self) -> Result<()> {
-        info!("{} Shutting down instance", self.log_prefix());
+        instance_log!(info, self, "Shutting down instance");
 
         match self.child.as_mut() {
             Some(child) => {
@@ -252,41 +900,287 @@ impl Instance {
                 child.wait()?;
             }
             None => {
-                info!("{} No instance to shut down", self.log_prefix());
+                instance_log!(info, self, "No instance to shut down");
             }
         }
         Ok(())
     }
 
+    // Ask the guest to shut down, `state` kills it if it doesn't in time
+    pub fn shutdown(&mut self) -> Result<()> {
+        instance_log!(info, self, "Shutting down instance cleanly");
+        self.shutdown_at = Some(Instant::now());
+
+        if let Err(e) = self.api.send_ctrl_alt_del() {
+            instance_log!(warn, self, "Could not ask guest to shut down: {}", e);
+            return self.stop();
+        }
+        Ok(())
+    }
+
+    // Stop the instance and remove its runner from GitHub, so it's started fresh once it's down
+    pub fn recycle(&mut self) -> Result<()> {
+        self.record_run();
+        self.shutdown()?;
+
+        instance_log!(debug, self, "Removing runner: '{}'", self.runner_name);
+        if let Err(e) = METRICS.github("remove_runner", || {
+            self.github.remove_runner(&self.runner_name)
+        }) {
+            instance_log!(warn, self, "Could not remove runner: {}", e);
+        }
+        Ok(())
+    }
+
+    // Returns why the instance should be recycled, if it ran too long or never picked up a job
+    pub fn expired(&mut self) -> Option<String> {
+        let started_at = self.started_at?;
+
+        if let Some(max_lifetime) = self.max_lifetime {
+            if started_at.elapsed() > max_lifetime {
+                return Some(format!(
+                    "Reached max lifetime of {}s",
+                    max_lifetime.as_secs()
+                ));
+            }
+        }
+
+        // The guest tells us when it picked up a job, GitHub is asked otherwise
+        if matches!(
+            self.guest_state(),
+            GuestState::Busy { .. } | GuestState::Finished { .. }
+        ) {
+            self.picked_up_job = true;
+        }
+
+        if let Some(timeout) = self.idle_registration_timeout {
+            let check_due = self
+                .last_idle_check
+                .is_none_or(|last_check| last_check.elapsed() > IDLE_CHECK_INTERVAL);
+
+            if !self.picked_up_job && started_at.elapsed() > timeout && check_due {
+                self.last_idle_check = Some(Instant::now());
+                match METRICS.github("runner", || self.github.runner(&self.runner_name)) {
+                    Ok(Some(runner)) if runner.busy => self.picked_up_job = true,
+                    Ok(_) => {
+                        return Some(format!("No job picked up within {}s", timeout.as_secs()))
+                    }
+                    Err(e) => instance_log!(warn, self, "Could not fetch runner: {}", e),
+                }
+            }
+        }
+
+        None
+    }
+
+    fn firecracker_command(&self) -> Command {
+        let _ = rm_rf(self.run_dir().join(API_SOCKET));
+
+        let mut command = match self.jail {
+            Some(ref jail) => jail.command(),
+            None => {
+                let mut command = Command::new("firecracker");
+                command.current_dir(&self.work_dir);
+                command
+            }
+        };
+        command.args(["--api-sock", API_SOCKET]);
+        command
+    }
+
+    // Spawn Firecracker and capture its output, optionally forwarding the serial console
+    fn spawn_firecracker(&self, args: &[&str], console: Option<Sender<String>>) -> Result<Child> {
+        self.start_logs()?;
+        self.start_events()?;
+
+        instance_log!(debug, self, "Running firecracker");
+        let mut child = self
+            .firecracker_command()
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(stdout) = child.stdout.take() {
+            self.logs.capture(stdout, CONSOLE_LOG, console)?;
+        }
+        if let Some(stderr) = child.stderr.take() {
+            self.logs.capture(stderr, FIRECRACKER_LOG, None)?;
+        }
+        Ok(child)
+    }
+
     pub fn start(&mut self) -> Result<()> {
+        if self.snapshot.is_some() {
+            return self.restore();
+        }
+
         self.setup_run()?;
+        let child = self.spawn_firecracker(&["--config-file", "config.json"], None)?;
+        self.child = Some(child);
+        self.started()
+    }
 
-        debug!("{} Running firecracker", self.log_prefix());
-        let child = Command::new("firecracker")
-            .args(["--config-file", "config.json", "--no-api"])
-            .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .current_dir(&self.work_dir)
-            .spawn()?;
+    fn started(&mut self) -> Result<()> {
+        self.started_at = Some(Instant::now());
+        self.picked_up_job = false;
+        self.last_idle_check = None;
+        Ok(())
+    }
+
+    // Start the instance from the role's snapshot, and hand it its identity
+    pub fn restore(&mut self) -> Result<()> {
+        self.runner_name = self.name();
+        let snapshot = self
+            .snapshot
+            .as_ref()
+            .ok_or(anyhow!("No snapshot configured"))?;
+        if !snapshot.exists() {
+            return Err(anyhow!("Snapshot in '{}' does not exist", snapshot.path));
+        }
+
+        self.recreate_overlay(&snapshot.overlay)?;
+        self.prepare_cache()?;
+        self.recreate_extra_drives()?;
+        self.setup_jail()?;
+
+        let (vmstate_path, memory_path) = match self.jail {
+            Some(ref jail) => (
+                jail.link_file(&snapshot.vmstate_path(), "vmstate")?,
+                jail.link_file(&snapshot.memory_path(), "memory")?,
+            ),
+            None => (snapshot.vmstate_path(), snapshot.memory_path()),
+        };
+
+        let mut child = self.spawn_firecracker(&[], None)?;
+
+        instance_log!(debug, self, "Restoring snapshot from: '{}'", snapshot.path);
+        let start = Instant::now();
+        let result = self.api.wait_for_socket().and_then(|_| {
+            self.api.put_logger(&self.logger())?;
+            self.api.put_metrics(&self.metrics())?;
+            self.api.load_snapshot(
+                &vmstate_path,
+                &memory_path,
+                &self.network_allocation.tap_name,
+            )?;
+            self.api.put_mmds(&self.identity()?)?;
+            self.api.resume()
+        });
+
+        // Don't leave a half restored VM running
+        if let Err(e) = result {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
         self.child = Some(child);
+        instance_log!(
+            info,
+            self,
+            "Restored snapshot in {}ms",
+            start.elapsed().as_millis()
+        );
+        self.started()
+    }
+
+    // Boot a template VM, wait until the guest reports it's ready and take a full snapshot of it.
+    pub fn create_snapshot(&mut self) -> Result<()> {
+        let snapshot = match self.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => return Ok(()),
+        };
+
+        instance_log!(info, self, "Creating snapshot in: '{}'", snapshot.path);
+        let _ = rm_rf(&snapshot.path);
+        fs::create_dir_all(&snapshot.path)?;
+
+        self.recreate_overlay(&self.overlay_template)?;
+        self.recreate_extra_drives()?;
+        self.setup_jail()?;
+        self.write_config(&self.config_with_boot_args(self.template_boot_args()))?;
+
+        // A jailed Firecracker can only write the snapshot inside the jail
+        let (vmstate_path, memory_path) = match self.jail {
+            Some(_) => (Utf8PathBuf::from("vmstate"), Utf8PathBuf::from("memory")),
+            None => (snapshot.vmstate_path(), snapshot.memory_path()),
+        };
+
+        let start = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        let mut child = self.spawn_firecracker(&["--config-file", "config.json"], Some(sender))?;
+
+        let result = self
+            .wait_for_guest_ready(receiver)
+            .and_then(|_| self.api.pause())
+            .and_then(|_| self.api.create_snapshot(&vmstate_path, &memory_path));
+
+        let _ = child.kill();
+        let _ = child.wait();
+        result?;
+
+        if self.jail.is_some() {
+            for (from, to) in [
+                (vmstate_path, snapshot.vmstate_path()),
+                (memory_path, snapshot.memory_path()),
+            ] {
+                fs::rename(self.run_dir().join(from), &to)?;
+                // Every instance runs as a different user, they all read the same snapshot
+                fs::set_permissions(&to, fs::Permissions::from_mode(0o644))?;
+            }
+        }
+
+        // The snapshot expects the overlay as it was when it was taken
+        snapshot
+            .overlay
+            .clone_from(&self.overlay, self.clone_strategy)?;
+
+        instance_log!(
+            info,
+            self,
+            "Created snapshot in {}s",
+            start.elapsed().as_secs()
+        );
         Ok(())
     }
 
+    fn wait_for_guest_ready(&self, console: Receiver<String>) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            let remaining = GUEST_READY_TIMEOUT
+                .checked_sub(start.elapsed())
+                .ok_or(anyhow!("Timed out waiting for the guest to become ready"))?;
+
+            match console.recv_timeout(remaining) {
+                Ok(line) if line.contains(GUEST_READY_MARKER) => return Ok(()),
+                Ok(_) => (),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(anyhow!("Timed out waiting for the guest to become ready"))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Guest stopped before it became ready"))
+                }
+            }
+        }
+    }
+
     pub fn run_once(&mut self) -> Result<()> {
         self.setup_run()?;
 
-        debug!("{} Running firecracker", self.log_prefix());
-        Command::new("firecracker")
-            .args(["--config-file", "config.json", "--no-api"])
-            .current_dir(&self.work_dir)
+        self.start_logs()?;
+        self.start_events()?;
+
+        instance_log!(debug, self, "Running firecracker");
+        self.firecracker_command()
+            .args(["--config-file", "config.json"])
             .status()
             .expect("Failed to start process");
         Ok(())
     }
 
     pub fn state(&mut self) -> InstanceState {
-        match self.child.as_mut() {
+        let state = match self.child.as_mut() {
             Some(child) => match child.try_wait() {
                 Ok(Some(status)) => {
                     if status.success() {
@@ -299,6 +1193,43 @@ impl Instance {
                 Err(_) => InstanceState::Errorred,
             },
             None => InstanceState::NotStarted,
+        };
+
+        // A shut down instance is started fresh, it didn't stop by itself
+        if let Some(shutdown_at) = self.shutdown_at {
+            if matches!(state, InstanceState::Running) {
+                if shutdown_at.elapsed() < SHUTDOWN_TIMEOUT {
+                    return state;
+                }
+                instance_log!(
+                    warn,
+                    self,
+                    "Guest did not shut down within {}s",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                );
+                if let Err(e) = self.stop() {
+                    instance_log!(error, self, "Failed to stop instance: {}", e);
+                    return InstanceState::Errorred;
+                }
+            }
+            self.reset();
+            return InstanceState::NotStarted;
+        }
+
+        if !matches!(state, InstanceState::Running | InstanceState::NotStarted) {
+            self.record_run();
+        }
+        state
+    }
+
+    // Record how long the instance ran, once, when it exited or is shut down
+    fn record_run(&mut self) {
+        if let Some(started_at) = self.started_at.take() {
+            METRICS.observe(
+                INSTANCE_RUN_SECONDS,
+                &[("role", &self.role)],
+                started_at.elapsed().as_secs_f64(),
+            );
         }
     }
 }
@@ -307,28 +1238,343 @@ impl Instance {
 mod tests {
     use super::*;
     use camino::Utf8PathBuf;
+    use std::net::Ipv4Addr;
+    use util::{inner, mock_inner, MTX};
 
     #[test]
     fn test_instance_setup() {
         let workdir: Utf8PathBuf = "/tmp/test_instance_setup".into();
         let github = GitHub::new("test", "test");
         let network_allocation = NetworkAllocation::new("eth0", 1);
-        let role = Role {
-            name: "test".to_string(),
-            kernel_image: Utf8PathBuf::from("kernel"),
-            kernel_cmdline: None,
-            rootfs_image: Utf8PathBuf::from("rootfs"),
-            cpus: 1,
-            memory_size: 1,
-            cache_size: 1,
-            max_cache_pct: 90,
-            overlay_size: 1,
-            instance_count: 1,
-            cache_paths: Vec::new(),
-            labels: Vec::new(),
-        };
+        let role = helpers::role();
 
-        let mut _instance = Instance::new(network_allocation, github.clone(), &workdir, &role, 1);
+        let mut _instance = Instance::new(
+            network_allocation,
+            github.clone(),
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
         //instance.setup().expect("Could not setup instance");
     }
+
+    #[test]
+    fn test_snapshot_template() {
+        let workdir: Utf8PathBuf = "/tmp/test_snapshot_template".into();
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let mut role = helpers::role();
+        role.snapshot = true;
+        role.cache_paths = vec![Utf8PathBuf::from("docker:/var/lib/docker")];
+        role.cache_quotas = HashMap::from([("npm".to_string(), 5), ("docker".to_string(), 20)]);
+        role.seed_cache = Some(Utf8PathBuf::from("seed-cache.ext4"));
+
+        let instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        let boot_args = instance.template_boot_args();
+        let config = instance.config_with_boot_args(boot_args.clone());
+
+        assert!(boot_args.contains("snapshot_template=1"));
+        assert!(boot_args.contains("cache_paths=\"docker:/var/lib/docker\""));
+        assert!(boot_args.contains("cache_format=ext4"));
+        assert!(boot_args.contains("cache_max_pct=90 cache_low_water_pct=70"));
+        assert!(boot_args.contains("cache_quotas=\"docker:20,npm:5\""));
+        assert!(boot_args.contains("seed_cache=vdd"));
+        assert_eq!(config.drives[3].path_on_host, "seed-cache.ext4");
+        assert!(config.drives[3].is_read_only);
+        assert!(!boot_args.contains("github_token"));
+        assert!(config.mmds_config.is_some());
+        assert_eq!(
+            instance.snapshot.map(|s| s.path),
+            Some(workdir.join("test/snapshot"))
+        );
+    }
+
+    #[test]
+    fn test_extra_drives() {
+        let workdir: Utf8PathBuf = "/tmp/test_extra_drives".into();
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let mut role = helpers::role();
+        role.seed_cache = Some(Utf8PathBuf::from("seed-cache.ext4"));
+        role.extra_drives = vec![
+            ExtraDrive {
+                path: Utf8PathBuf::from("/srv/fixtures.ext4"),
+                mount_point: Utf8PathBuf::from("/fixtures"),
+                read_only: true,
+                cache_type: None,
+            },
+            ExtraDrive {
+                path: Utf8PathBuf::from("/srv/scratch.ext4"),
+                mount_point: Utf8PathBuf::from("/scratch"),
+                read_only: false,
+                cache_type: Some("Writeback".to_string()),
+            },
+        ];
+
+        let instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        let boot_args = instance.template_boot_args();
+        let config = instance.config_with_boot_args(boot_args.clone());
+
+        assert!(boot_args.contains("extra_drives=\"vde:/fixtures:ro,vdf:/scratch:rw\""));
+        assert_eq!(config.drives.len(), 6);
+        assert_eq!(config.drives[4].path_on_host, "/srv/fixtures.ext4");
+        assert!(config.drives[4].is_read_only);
+        assert_eq!(config.drives[5].path_on_host, "drive-1.img");
+        assert!(!config.drives[5].is_read_only);
+        assert_eq!(config.drives[5].cache_type.as_deref(), Some("Writeback"));
+    }
+
+    #[test]
+    fn test_check_drives() {
+        let mut role = helpers::role();
+        let extra_drive = ExtraDrive {
+            path: Utf8PathBuf::from("/srv/fixtures.ext4"),
+            mount_point: Utf8PathBuf::from("/fixtures"),
+            read_only: true,
+            cache_type: None,
+        };
+        role.extra_drives = vec![extra_drive; MAX_DRIVES - EXTRA_DRIVES_START];
+        assert!(check_drives(&role).is_ok());
+
+        role.seed_cache = Some(Utf8PathBuf::from("seed-cache.ext4"));
+        assert!(check_drives(&role).is_err());
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let workdir: Utf8PathBuf = "/tmp/test_max_lifetime".into();
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let mut role = helpers::role();
+        role.max_lifetime = Some(60);
+
+        let mut instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        assert_eq!(instance.expired(), None);
+
+        instance.started_at = Some(Instant::now());
+        assert_eq!(instance.expired(), None);
+
+        instance.started_at = Instant::now().checked_sub(Duration::from_secs(61));
+        assert_eq!(
+            instance.expired(),
+            Some("Reached max lifetime of 60s".to_string())
+        );
+    }
+
+    #[test]
+    fn test_shutdown_timeout() {
+        let workdir: Utf8PathBuf = "/tmp/test_shutdown_timeout".into();
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let role = helpers::role();
+
+        let mut instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        instance.child = Some(Command::new("sleep").arg("60").spawn().unwrap());
+        instance.started_at = Some(Instant::now());
+        instance.record_run();
+        assert_eq!(instance.started_at, None);
+
+        instance.shutdown_at = Some(Instant::now());
+        assert!(matches!(instance.state(), InstanceState::Running));
+
+        instance.shutdown_at = Instant::now().checked_sub(SHUTDOWN_TIMEOUT);
+        assert!(matches!(instance.state(), InstanceState::NotStarted));
+        assert!(instance.child.is_none());
+        assert_eq!(instance.shutdown_at, None);
+    }
+
+    #[test]
+    fn test_cleanup_keeps_persistent_cache() {
+        let _m = MTX.lock();
+        let workdir: Utf8PathBuf = "/tmp/test_cleanup_keeps_persistent_cache".into();
+        let _ = fs::remove_dir_all(&workdir);
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let mut role = helpers::role();
+        role.cache_policy = CachePolicy::Persistent;
+
+        let instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        fs::create_dir_all(&instance.work_dir).expect("Could not create work dir");
+        fs::write(instance.cache.path_with_filename(), "cache").expect("Could not write cache");
+        fs::write(instance.overlay.path_with_filename(), "overlay")
+            .expect("Could not write overlay");
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c)
+                    == "rm -rf /tmp/test_cleanup_keeps_persistent_cache/test/1/overlay.ext4"
+            })
+            .returning(inner::internal_exec);
+
+        instance.cleanup().expect("Could not cleanup instance");
+        ctx.checkpoint();
+
+        assert!(instance.cache.path_with_filename().exists());
+        assert!(!instance.overlay.path_with_filename().exists());
+
+        let _ = fs::remove_dir_all(&workdir);
+    }
+
+    #[test]
+    fn test_recreate_cache_from_baseline() {
+        let _m = MTX.lock();
+        let workdir: Utf8PathBuf = "/tmp/test_recreate_cache_from_baseline".into();
+        let _ = fs::remove_dir_all(&workdir);
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let mut role = helpers::role();
+        role.cache_policy = CachePolicy::Promote;
+
+        let instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            None,
+        );
+        let cache_baseline = instance.cache_baseline.as_ref().expect("No cache baseline");
+        assert_eq!(
+            cache_baseline.path_with_filename(),
+            workdir.join("test/cache-baseline.ext4")
+        );
+
+        fs::create_dir_all(&instance.work_dir).expect("Could not create work dir");
+        fs::File::create(cache_baseline.path_with_filename())
+            .and_then(|file| file.set_len(1024 * 1024 * 1024))
+            .expect("Could not create baseline");
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c)
+                    == "rm -rf /tmp/test_recreate_cache_from_baseline/test/1/cache.ext4"
+            })
+            .returning(inner::internal_exec);
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c)
+                    == "cp --sparse=always /tmp/test_recreate_cache_from_baseline/test/cache-baseline.ext4 /tmp/test_recreate_cache_from_baseline/test/1/cache.ext4"
+            })
+            .returning(inner::internal_exec);
+
+        instance.recreate_cache().expect("Could not recreate cache");
+        ctx.checkpoint();
+        assert!(instance.cache.exists());
+
+        let _ = fs::remove_dir_all(&workdir);
+    }
+
+    #[test]
+    fn test_jailed_config() {
+        let workdir: Utf8PathBuf = "/tmp/test_jailed_config".into();
+        let github = GitHub::new("test", "test");
+        let network_allocation = NetworkAllocation::new("eth0", 1);
+        let role = helpers::role();
+        let jailer = JailerConfig {
+            jailer_binary: "/usr/bin/jailer".into(),
+            firecracker_binary: "/usr/bin/firecracker".into(),
+            uid_start: 10000,
+            gid_start: 10000,
+            veth_network: Ipv4Addr::new(10, 200, 0, 0),
+        };
+
+        let instance = Instance::new(
+            network_allocation,
+            github,
+            &workdir,
+            &role,
+            1,
+            CloneStrategy::Sparse,
+            Some(&jailer),
+        );
+        let config = instance.config_with_boot_args(String::new());
+
+        assert_eq!(
+            instance.run_dir(),
+            workdir.join("jailer/firecracker/test-1/root")
+        );
+        assert_eq!(config.boot_source.kernel_image_path, "vmlinux");
+        assert_eq!(config.drives[0].path_on_host, "rootfs.ext4");
+        let namespace = instance.network_allocation.namespace.expect("No namespace");
+        assert_eq!(namespace.uid, 10001);
+        assert_eq!(namespace.host_ip, Ipv4Addr::new(10, 200, 1, 1));
+    }
+
+    mod helpers {
+        use super::*;
+
+        pub fn role() -> Role {
+            Role {
+                name: "test".to_string(),
+                kernel_image: Utf8PathBuf::from("kernel"),
+                kernel_cmdline: None,
+                rootfs_image: Utf8PathBuf::from("rootfs"),
+                cpus: 1,
+                memory_size: 1,
+                cache_size: 1,
+                max_cache_pct: 90,
+                overlay_size: 1,
+                instance_count: 1,
+                cache_paths: Vec::new(),
+                labels: Vec::new(),
+                snapshot: false,
+                boot_logs: 5,
+                cache_format: DiskFormat::Ext4,
+                cache_policy: CachePolicy::Persistent,
+                cache_template: false,
+                cache_trim: false,
+                cache_low_water_pct: 70,
+                cache_quotas: HashMap::new(),
+                seed_cache: None,
+                extra_drives: Vec::new(),
+                max_lifetime: None,
+                idle_registration_timeout: None,
+            }
+        }
+    }
 }
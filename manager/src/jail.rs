@@ -0,0 +1,109 @@
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use config::manager::JailerConfig;
+use std::fs;
+use std::os::unix::fs::chown;
+use std::process::Command;
+use util::{fs::rm_rf, mount};
+
+// Read-only files shared between instances are bind mounted into the jail,
+// so we don't change the ownership of the originals.
+const SHARED_FILES: [&str; 5] = [
+    "vmlinux",
+    "rootfs.ext4",
+    "seed-cache.img",
+    "vmstate",
+    "memory",
+];
+// Read-only extra drives of a role are shared as well
+pub const SHARED_DRIVE_PREFIX: &str = "shared-drive-";
+
+// A chroot for the Firecracker jailer, see:
+// https://github.com/firecracker-microvm/firecracker/blob/main/docs/jailer.md
+#[derive(Debug)]
+pub struct Jail {
+    pub id: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub netns: String,
+    chroot_base: Utf8PathBuf,
+    jailer_binary: Utf8PathBuf,
+    firecracker_binary: Utf8PathBuf,
+}
+
+impl Jail {
+    pub fn new(config: &JailerConfig, run_path: &Utf8Path, id: &str, idx: u8, netns: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            uid: config.uid_start + idx as u32,
+            gid: config.gid_start + idx as u32,
+            netns: netns.to_string(),
+            chroot_base: run_path.join("jailer"),
+            jailer_binary: config.jailer_binary.clone(),
+            firecracker_binary: config.firecracker_binary.clone(),
+        }
+    }
+
+    // The jailer chroots into: <chroot_base>/<exec file name>/<id>/root
+    pub fn root(&self) -> Utf8PathBuf {
+        self.chroot_base
+            .join(self.firecracker_binary.file_name().unwrap_or("firecracker"))
+            .join(&self.id)
+            .join("root")
+    }
+
+    // Start with an empty chroot on every boot
+    pub fn setup(&self) -> Result<()> {
+        self.cleanup()?;
+        fs::create_dir_all(self.root())?;
+        chown(self.root(), Some(self.uid), Some(self.gid))?;
+        Ok(())
+    }
+
+    // Make a file available in the jail as `name`. Shared files are bind mounted read-only,
+    // per instance files are hard linked and handed to the jail's user.
+    pub fn link_file(&self, from: &Utf8Path, name: &str) -> Result<Utf8PathBuf> {
+        let to = self.root().join(name);
+        let _ = fs::remove_file(&to);
+
+        if SHARED_FILES.contains(&name) || name.starts_with(SHARED_DRIVE_PREFIX) {
+            fs::write(&to, "")?;
+            mount::mount_bind(from, &to, true)?;
+        } else {
+            fs::hard_link(from, &to)?;
+            chown(&to, Some(self.uid), Some(self.gid))?;
+        }
+
+        Ok(Utf8PathBuf::from(name))
+    }
+
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.jailer_binary);
+        command
+            .args(["--id", &self.id])
+            .args(["--exec-file", self.firecracker_binary.as_str()])
+            .args(["--uid", &self.uid.to_string()])
+            .args(["--gid", &self.gid.to_string()])
+            .args(["--chroot-base-dir", self.chroot_base.as_str()])
+            .args(["--netns", &format!("/var/run/netns/{}", self.netns)])
+            .args(["--cgroup-version", "2"])
+            .arg("--");
+        command
+    }
+
+    pub fn cleanup(&self) -> Result<()> {
+        for name in SHARED_FILES {
+            let _ = mount::unmount(self.root().join(name));
+        }
+        if let Ok(entries) = self.root().read_dir_utf8() {
+            for entry in entries.flatten() {
+                if entry.file_name().starts_with(SHARED_DRIVE_PREFIX) {
+                    let _ = mount::unmount(entry.path());
+                }
+            }
+        }
+        // Remove the whole jail, including the `dev` and `run` directories created by the jailer
+        let _ = rm_rf(self.root().parent().unwrap_or(&self.root()));
+        Ok(())
+    }
+}
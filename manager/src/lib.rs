@@ -1,20 +1,47 @@
 use crate::{
     instance::{Instance, InstanceState},
+    metrics::{INSTANCES, INSTANCE_FAILURES, INSTANCE_RESTARTS, INSTANCE_STARTS, METRICS},
     network::{Forwarding, NetworkAllocation},
 };
 use anyhow::Result;
-use config::manager::ManagerConfig;
+use config::image::ImageManifest;
+use config::manager::{ManagerConfig, Role};
 use github::GitHub;
 use log::*;
 use signal_hook::{consts::SIGINT, iterator::Signals};
+use std::collections::BTreeMap;
+use std::fs;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use store::Store;
+use util::fs::CloneStrategy;
+
+// The number of log lines we show when an instance errored
+const ERROR_LOG_LINES: usize = 20;
+
+// Log with the role, index and runner name of an instance as structured fields
+macro_rules! instance_log {
+    ($level:ident, $instance:expr, $($arg:tt)+) => {
+        log::$level!(
+            role = $instance.role(),
+            instance_idx = $instance.idx(),
+            runner_name = $instance.runner_name();
+            $($arg)+
+        )
+    };
+}
 
 pub mod disk;
+pub mod events;
+pub mod firecracker;
 pub mod instance;
+pub mod jail;
+pub mod logs;
+pub mod metrics;
 pub mod network;
+pub mod snapshot;
 
 pub struct Manager {
     pub config: ManagerConfig,
@@ -43,12 +70,32 @@ impl Manager {
     }
 
     pub fn setup(&mut self) -> Result<()> {
+        for role in &self.config.roles {
+            instance::check_drives(role)?;
+        }
+
         let network_forwarding = Forwarding::new(&self.config.network_interface);
         network_forwarding.setup()?;
 
         let github = GitHub::new(&self.config.github_org, &self.config.github_pat);
 
+        if let Some(ref metrics_address) = self.config.metrics_address {
+            metrics::serve(metrics_address)?;
+        }
+
+        self.resolve_from_store()?;
+
+        fs::create_dir_all(&self.config.run_path)?;
+        let clone_strategy = CloneStrategy::detect(&self.config.run_path);
+        info!(
+            "Cloning disks in '{}' with strategy: {}",
+            self.config.run_path, clone_strategy
+        );
+
         for role in &self.config.roles {
+            let first_instance = self.instances.len();
+            log_image_manifest(role);
+
             for _ in 0..role.instance_count {
                 let idx = self.instances.len() as u8 + 1;
 
@@ -61,10 +108,19 @@ impl Manager {
                     &self.config.run_path,
                     role,
                     idx,
+                    clone_strategy,
+                    self.config.jailer.as_ref(),
                 );
                 instance.setup()?;
                 self.instances.push(instance);
             }
+
+            // The first instance of the role boots the template for the snapshot
+            if role.snapshot {
+                if let Some(instance) = self.instances.get_mut(first_instance) {
+                    instance.create_snapshot()?;
+                }
+            }
         }
         Ok(())
     }
@@ -74,32 +130,76 @@ impl Manager {
             if self.shutdown.load(Ordering::Relaxed) {
                 info!("Shutting down.");
                 for instance in &mut self.instances {
-                    info!("{} Stopping instance", instance.log_prefix());
+                    instance_log!(info, instance, "Stopping instance");
 
                     if let Err(e) = instance.stop() {
-                        error!("{} Failed to stop instance: {}", instance.log_prefix(), e);
+                        instance_log!(error, instance, "Failed to stop instance: {}", e);
                     }
                     let _ = instance.cleanup();
                 }
                 break;
             }
 
+            let mut instance_counts = BTreeMap::new();
+            for role in &self.config.roles {
+                for state in InstanceState::ALL {
+                    instance_counts.insert((role.slug(), state.to_string()), 0);
+                }
+            }
+
             for instance in &mut self.instances {
-                match instance.state() {
-                    InstanceState::Running => (),
+                let state = instance.state();
+                *instance_counts
+                    .entry((instance.role().to_string(), state.to_string()))
+                    .or_default() += 1;
+
+                match state {
+                    InstanceState::Running => {
+                        if let Some(reason) = instance.expired() {
+                            instance_log!(info, instance, "{}, recycling instance", reason);
+                            if let Err(e) = instance.recycle() {
+                                instance_log!(error, instance, "Failed to recycle instance: {}", e);
+                            }
+                        }
+                    }
                     InstanceState::NotStarted | InstanceState::NotRunning => {
-                        info!("{} Starting instance", instance.log_prefix());
-                        if let Err(e) = instance.start() {
-                            error!("{} Failed to start instance: {}", instance.log_prefix(), e);
+                        instance_log!(info, instance, "Starting instance");
+                        if matches!(state, InstanceState::NotRunning) {
+                            METRICS.inc(INSTANCE_RESTARTS, &[("role", instance.role())]);
+                        }
+                        match instance.start() {
+                            Ok(_) => METRICS.inc(INSTANCE_STARTS, &[("role", instance.role())]),
+                            Err(e) => {
+                                METRICS.inc(INSTANCE_FAILURES, &[("role", instance.role())]);
+                                instance_log!(error, instance, "Failed to start instance: {}", e);
+                            }
                         }
                     }
                     InstanceState::Errorred => {
-                        error!("{} Instance has errored.", instance.log_prefix());
+                        METRICS.inc(INSTANCE_FAILURES, &[("role", instance.role())]);
+                        instance_log!(
+                            error,
+                            instance,
+                            "Instance has errored, guest was {}.",
+                            instance.guest_state()
+                        );
+                        for line in instance.log_tail(ERROR_LOG_LINES) {
+                            instance_log!(error, instance, "{}", line);
+                        }
                         thread::sleep(Duration::from_secs(20));
                         instance.reset();
                     }
                 }
             }
+
+            for ((role, state), count) in instance_counts {
+                METRICS.set(
+                    INSTANCES,
+                    &[("role", &role), ("state", &state)],
+                    count as f64,
+                );
+            }
+
             thread::sleep(Duration::from_secs(1));
         }
 
@@ -110,6 +210,7 @@ impl Manager {
         let network_forwarding = Forwarding::new(&self.config.network_interface);
         let network_allocation = NetworkAllocation::new(&self.config.network_interface, idx);
         let github = GitHub::new(&self.config.github_org, &self.config.github_pat);
+        self.resolve_from_store()?;
         let mut role = self
             .config
             .roles
@@ -118,11 +219,20 @@ impl Manager {
             .expect("Could not find role.")
             .clone();
 
-        // Set output to console
-        role.kernel_cmdline = match role.kernel_cmdline {
-            Some(ref cmdline) => Some(format!("console=ttyS0 {}", cmdline)),
-            None => Some("console=ttyS0".to_string()),
-        };
+        log_image_manifest(&role);
+
+        // Always cold boot, so we can follow the whole boot
+        if role.snapshot {
+            info!("Ignoring snapshot for role: `{}` in debug mode", role.name);
+            role.snapshot = false;
+        }
+
+        fs::create_dir_all(&self.config.run_path)?;
+        let clone_strategy = CloneStrategy::detect(&self.config.run_path);
+        debug!(
+            "Cloning disks in '{}' with strategy: {}",
+            self.config.run_path, clone_strategy
+        );
 
         let mut instance = Instance::new(
             network_allocation,
@@ -130,6 +240,8 @@ impl Manager {
             &self.config.run_path,
             &role,
             idx,
+            clone_strategy,
+            self.config.jailer.as_ref(),
         );
         network_forwarding.setup()?;
         instance.setup()?;
@@ -139,4 +251,59 @@ impl Manager {
         instance.cleanup()?;
         Ok(())
     }
+
+    // Point roles that name an image or kernel in the store at its file, and register the files
+    // we use so they're not garbage collected while we run
+    fn resolve_from_store(&mut self) -> Result<()> {
+        let images = Store::images(&self.config.store_path);
+        let kernels = Store::kernels(&self.config.store_path);
+        for role in &mut self.config.roles {
+            let rootfs_image = images.resolve_path(&role.rootfs_image)?;
+            if rootfs_image != role.rootfs_image {
+                debug!(
+                    "Role '{}' uses image '{}' from: {}",
+                    role.name, role.rootfs_image, rootfs_image
+                );
+                role.rootfs_image = rootfs_image;
+            }
+
+            let kernel_image = kernels.resolve_path(&role.kernel_image)?;
+            if kernel_image != role.kernel_image {
+                debug!(
+                    "Role '{}' uses kernel '{}' from: {}",
+                    role.name, role.kernel_image, kernel_image
+                );
+                role.kernel_image = kernel_image;
+            }
+        }
+
+        let paths: Vec<_> = self
+            .config
+            .roles
+            .iter()
+            .flat_map(|role| [role.rootfs_image.clone(), role.kernel_image.clone()])
+            .collect();
+        if let Err(e) = images.register(&paths) {
+            warn!(
+                "Could not register images in store '{}': {}",
+                self.config.store_path, e
+            );
+        }
+        Ok(())
+    }
+}
+
+// Log where the rootfs image of a role came from, if it was built with a manifest
+fn log_image_manifest(role: &Role) {
+    let manifest_path = ImageManifest::sidecar_path(&role.rootfs_image);
+    match ImageManifest::from_file(&manifest_path) {
+        Ok(manifest) => info!(
+            "Role '{}' uses image '{}', {}",
+            role.name, role.rootfs_image, manifest
+        ),
+        Err(e) => debug!(
+            "No manifest for image '{}' in '{}': {}",
+            role.rootfs_image, manifest_path, e
+        ),
+    }
 }
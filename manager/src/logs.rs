@@ -0,0 +1,279 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use util::fs::{mkfifo, rm_rf};
+
+pub const CONSOLE_LOG: &str = "console.log";
+pub const FIRECRACKER_LOG: &str = "firecracker.log";
+pub const METRICS_LOG: &str = "metrics.log";
+
+// Kernel args that shouldn't end up in the logs, the kernel echoes its command line on boot
+const SECRET_ARGS: [&str; 1] = ["github_token="];
+const REDACTED: &str = "[REDACTED]";
+
+// Firecracker opens its log FIFO when it's configured, if it didn't by then it's not going to
+const FIFO_OPEN_TIMEOUT: Duration = Duration::from_secs(60);
+const FIFO_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The guest decides how much ends up in the logs, so each log of a boot is cut off at this size.
+// An instance uses at most `boot_logs` times this for every log on disk.
+pub const MAX_LOG_SIZE: u64 = 16 * 1024 * 1024;
+const TRUNCATED: &[u8] = b"\n[TRUNCATED]\n";
+
+// The logs of the last boots of an instance, the current boot is always in `boot-0`
+#[derive(Debug)]
+pub struct BootLogs {
+    pub path: Utf8PathBuf,
+    pub keep: u8,
+}
+
+impl BootLogs {
+    pub fn new(path: impl AsRef<Utf8Path>, keep: u8) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            keep: keep.max(1),
+        }
+    }
+
+    pub fn boot_path(&self, boot: u8) -> Utf8PathBuf {
+        self.path.join(format!("boot-{}", boot))
+    }
+
+    pub fn current_path(&self) -> Utf8PathBuf {
+        self.boot_path(0)
+    }
+
+    // Move the logs of the previous boots up, dropping the oldest, and start with an empty boot
+    pub fn rotate(&self) -> std::io::Result<()> {
+        rm_rf(self.boot_path(self.keep - 1))?;
+        for boot in (0..self.keep - 1).rev() {
+            if self.boot_path(boot).exists() {
+                fs::rename(self.boot_path(boot), self.boot_path(boot + 1))?;
+            }
+        }
+        fs::create_dir_all(self.current_path())?;
+        Ok(())
+    }
+
+    fn open(&self, name: &str) -> std::io::Result<LimitedLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.current_path().join(name))?;
+        let size = file.metadata()?.len();
+        Ok(LimitedLog::new(file, MAX_LOG_SIZE.saturating_sub(size)))
+    }
+
+    // Copy everything from the reader into the log of the current boot, optionally
+    // forwarding the lines to a channel.
+    pub fn capture(
+        &self,
+        reader: impl Read + Send + 'static,
+        name: &str,
+        lines: Option<Sender<String>>,
+    ) -> std::io::Result<()> {
+        let mut file = self.open(name)?;
+
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                let line = redact(&line);
+                let _ = writeln!(file, "{}", line);
+                if let Some(ref sender) = lines {
+                    let _ = sender.send(line);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Create a FIFO for Firecracker to write to, and capture it into the log of the current boot.
+    // We reach the end once Firecracker exits, or when it never opened the FIFO.
+    pub fn capture_fifo(&self, fifo_path: impl AsRef<Utf8Path>, name: &str) -> std::io::Result<()> {
+        let fifo_path = fifo_path.as_ref().to_path_buf();
+        let _ = fs::remove_file(&fifo_path);
+        mkfifo(&fifo_path)?;
+
+        let mut file = self.open(name)?;
+        thread::spawn(move || {
+            let _ = copy_fifo(&fifo_path, &mut file);
+        });
+        Ok(())
+    }
+
+    // The last lines of a log of the current boot
+    pub fn tail(&self, name: &str, count: usize) -> Vec<String> {
+        let content = fs::read_to_string(self.current_path().join(name)).unwrap_or_default();
+        let lines = content.lines().collect::<Vec<&str>>();
+
+        lines[lines.len().saturating_sub(count)..]
+            .iter()
+            .map(|line| line.to_string())
+            .collect()
+    }
+}
+
+// A log file that drops everything written after its limit, after noting it was truncated.
+// Writes never fail because of the limit, so the guest can't block on a full log.
+struct LimitedLog {
+    file: File,
+    remaining: u64,
+    truncated: bool,
+}
+
+impl LimitedLog {
+    fn new(file: File, remaining: u64) -> Self {
+        Self {
+            file,
+            remaining,
+            truncated: false,
+        }
+    }
+}
+
+impl Write for LimitedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = buf.len().min(self.remaining as usize);
+        if count > 0 {
+            self.file.write_all(&buf[..count])?;
+            self.remaining -= count as u64;
+        }
+        if count < buf.len() && !self.truncated {
+            self.truncated = true;
+            self.file.write_all(TRUNCATED)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Replace the values of secret kernel args in a log line
+fn redact(line: &str) -> String {
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some((start, arg)) = SECRET_ARGS
+        .iter()
+        .filter_map(|arg| rest.find(arg).map(|start| (start, arg)))
+        .min()
+    {
+        let value_start = start + arg.len();
+        let value_end = rest[value_start..]
+            .find(char::is_whitespace)
+            .map_or(rest.len(), |end| value_start + end);
+        redacted.push_str(&rest[..value_start]);
+        redacted.push_str(REDACTED);
+        rest = &rest[value_end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+// Opening a FIFO for reading blocks until there's a writer, which there never is when
+// Firecracker dies before it got to it. Open it non-blocking and wait for Firecracker to
+// connect for a while, then block on reading until it closes it.
+fn copy_fifo(fifo_path: &Utf8Path, file: &mut LimitedLog) -> std::io::Result<()> {
+    let mut fifo = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(fifo_path)?;
+
+    let deadline = Instant::now() + FIFO_OPEN_TIMEOUT;
+    let mut buffer = [0; 8192];
+    loop {
+        match fifo.read(&mut buffer) {
+            // Without a writer reading reaches the end right away
+            Ok(0) if Instant::now() < deadline => thread::sleep(FIFO_POLL_INTERVAL),
+            Ok(0) => return Ok(()),
+            Ok(count) => {
+                file.write_all(&buffer[..count])?;
+                break;
+            }
+            // There's a writer, it just didn't write anything yet
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    fcntl(fifo.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty()))?;
+    std::io::copy(&mut fifo, file)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::{inner, mock_inner, MTX};
+
+    #[test]
+    fn test_rotate_and_tail() {
+        let _m = MTX.lock();
+        let path: Utf8PathBuf = std::env::temp_dir()
+            .join("test_rotate_and_tail")
+            .try_into()
+            .expect("Invalid path");
+        let _ = fs::remove_dir_all(&path);
+        let logs = BootLogs::new(&path, 2);
+
+        let rm_oldest = format!("rm -rf {}", logs.boot_path(1));
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(move |c| inner::to_string(c) == rm_oldest)
+            .times(3)
+            .returning(inner::internal_exec);
+
+        for boot in 0..3 {
+            logs.rotate().expect("Could not rotate logs");
+            fs::write(
+                logs.current_path().join(CONSOLE_LOG),
+                format!("first\nboot {}\n", boot),
+            )
+            .expect("Could not write log");
+        }
+        ctx.checkpoint();
+
+        assert_eq!(logs.tail(CONSOLE_LOG, 1), vec!["boot 2"]);
+        assert_eq!(logs.tail(CONSOLE_LOG, 5), vec!["first", "boot 2"]);
+        assert!(logs.boot_path(1).exists());
+        assert!(!logs.boot_path(2).exists());
+
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Command line: console=ttyS0 github_token=secret github_org=org"),
+            "Command line: console=ttyS0 github_token=[REDACTED] github_org=org"
+        );
+        assert_eq!(
+            redact("github_token=a github_token=b"),
+            "github_token=[REDACTED] github_token=[REDACTED]"
+        );
+        assert_eq!(redact("github_token="), "github_token=[REDACTED]");
+        assert_eq!(redact("no secrets"), "no secrets");
+    }
+
+    #[test]
+    fn test_limited_log() {
+        let path = std::env::temp_dir().join("test_limited_log.log");
+        let file = File::create(&path).expect("Could not create log");
+        let mut log = LimitedLog::new(file, 8);
+
+        log.write_all(b"12345").expect("Could not write");
+        log.write_all(b"67890").expect("Could not write");
+        log.write_all(b"more").expect("Could not write");
+
+        let content = fs::read(&path).expect("Could not read log");
+        assert_eq!(content, b"12345678\n[TRUNCATED]\n");
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// So idle or slow clients don't keep their connection (and thread) around
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LINE: u64 = 8192;
+
+pub const INSTANCES: &str = "actions_runner_instances";
+pub const INSTANCE_STARTS: &str = "actions_runner_instance_starts_total";
+pub const INSTANCE_FAILURES: &str = "actions_runner_instance_failures_total";
+pub const INSTANCE_RESTARTS: &str = "actions_runner_instance_restarts_total";
+pub const INSTANCE_RUN_SECONDS: &str = "actions_runner_instance_run_seconds";
+pub const ROOTFS_COPY_SECONDS: &str = "actions_runner_rootfs_copy_seconds";
+pub const CACHE_USAGE_PERCENT: &str = "actions_runner_cache_usage_percent";
+pub const CACHE_WIPES: &str = "actions_runner_cache_wipes_total";
+pub const CACHE_EVICTIONS: &str = "actions_runner_cache_evictions_total";
+pub const CACHE_PROMOTIONS: &str = "actions_runner_cache_promotions_total";
+pub const GITHUB_REQUEST_SECONDS: &str = "actions_runner_github_request_seconds";
+pub const GITHUB_ERRORS: &str = "actions_runner_github_errors_total";
+
+// The type and help text of every metric, in the order they're rendered
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (INSTANCES, "gauge", "Instances per role and state"),
+    (INSTANCE_STARTS, "counter", "Instances started"),
+    (
+        INSTANCE_FAILURES,
+        "counter",
+        "Instances that errored or failed to start",
+    ),
+    (
+        INSTANCE_RESTARTS,
+        "counter",
+        "Instances started again after they exited",
+    ),
+    (
+        INSTANCE_RUN_SECONDS,
+        "summary",
+        "Time from boot until the instance exited",
+    ),
+    (
+        ROOTFS_COPY_SECONDS,
+        "summary",
+        "Time spent cloning the rootfs overlay of an instance",
+    ),
+    (
+        CACHE_USAGE_PERCENT,
+        "gauge",
+        "Usage of the cache disk of an instance",
+    ),
+    (
+        CACHE_WIPES,
+        "counter",
+        "Cache disks wiped because they were too full",
+    ),
+    (
+        CACHE_EVICTIONS,
+        "counter",
+        "Cache roots evicted because the cache was too full or over quota",
+    ),
+    (
+        CACHE_PROMOTIONS,
+        "counter",
+        "Caches of successful jobs promoted to the baseline of their role",
+    ),
+    (
+        GITHUB_REQUEST_SECONDS,
+        "summary",
+        "Latency of GitHub API calls",
+    ),
+    (GITHUB_ERRORS, "counter", "Failed GitHub API calls"),
+];
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Default)]
+struct Summary {
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<(&'static str, Labels), f64>>,
+    summaries: Mutex<BTreeMap<(&'static str, Labels), Summary>>,
+}
+
+fn labels(labels: &[(&str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn render_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}=\"{}\"",
+                key,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    format!("{{{}}}", labels)
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, label_values: &[(&str, &str)]) {
+        let mut values = self.values.lock().expect("Metrics lock poisoned");
+        *values.entry((name, labels(label_values))).or_default() += 1.0;
+    }
+
+    pub fn set(&self, name: &'static str, label_values: &[(&str, &str)], value: f64) {
+        let mut values = self.values.lock().expect("Metrics lock poisoned");
+        values.insert((name, labels(label_values)), value);
+    }
+
+    pub fn observe(&self, name: &'static str, label_values: &[(&str, &str)], value: f64) {
+        let mut summaries = self.summaries.lock().expect("Metrics lock poisoned");
+        let summary = summaries.entry((name, labels(label_values))).or_default();
+        summary.sum += value;
+        summary.count += 1;
+    }
+
+    // Time a GitHub API call, and count it if it failed
+    pub fn github<T>(&self, call: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = f();
+        self.observe(
+            GITHUB_REQUEST_SECONDS,
+            &[("call", call)],
+            start.elapsed().as_secs_f64(),
+        );
+        if result.is_err() {
+            self.inc(GITHUB_ERRORS, &[("call", call)]);
+        }
+        result
+    }
+
+    // Render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let values = self.values.lock().expect("Metrics lock poisoned");
+        let summaries = self.summaries.lock().expect("Metrics lock poisoned");
+        let mut output = String::new();
+
+        for (name, kind, help) in DESCRIPTIONS {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+
+            for ((_, labels), value) in values.iter().filter(|((n, _), _)| n == name) {
+                let _ = writeln!(output, "{}{} {}", name, render_labels(labels), value);
+            }
+            for ((_, labels), summary) in summaries.iter().filter(|((n, _), _)| n == name) {
+                let labels = render_labels(labels);
+                let _ = writeln!(output, "{}_sum{} {}", name, labels, summary.sum);
+                let _ = writeln!(output, "{}_count{} {}", name, labels, summary.count);
+            }
+        }
+        output
+    }
+}
+
+// Serve `/metrics` on the given address in the background
+pub fn serve(address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving metrics on: http://{}/metrics", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = respond(stream) {
+                            debug!("Could not serve metrics: {}", e);
+                        }
+                    });
+                }
+                Err(e) => debug!("Could not accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LINE)).read_line(&mut request_line)?;
+
+    let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", METRICS.render()),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.inc(INSTANCE_STARTS, &[("role", "test")]);
+        metrics.inc(INSTANCE_STARTS, &[("role", "test")]);
+        metrics.set(
+            CACHE_USAGE_PERCENT,
+            &[("role", "test"), ("instance", "1")],
+            42.0,
+        );
+        metrics.observe(ROOTFS_COPY_SECONDS, &[("role", "test")], 1.5);
+
+        let output = metrics.render();
+
+        assert!(output.contains("# TYPE actions_runner_instance_starts_total counter\n"));
+        assert!(output.contains("actions_runner_instance_starts_total{role=\"test\"} 2\n"));
+        assert!(output
+            .contains("actions_runner_cache_usage_percent{role=\"test\",instance=\"1\"} 42\n"));
+        assert!(output.contains("actions_runner_rootfs_copy_seconds_sum{role=\"test\"} 1.5\n"));
+        assert!(output.contains("actions_runner_rootfs_copy_seconds_count{role=\"test\"} 1\n"));
+    }
+}
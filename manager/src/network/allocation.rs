@@ -10,6 +10,19 @@ pub struct NetworkAllocation {
     pub guest_mac: String,
     pub client_ip: Ipv4Addr,
     pub tap_name: String,
+    pub namespace: Option<NetworkNamespace>,
+}
+
+// A network namespace for a jailed instance. The tap device lives in the namespace,
+// which is connected to the host through a veth pair.
+#[derive(Debug)]
+pub struct NetworkNamespace {
+    pub name: String,
+    pub veth_name: String,
+    pub host_ip: Ipv4Addr,
+    pub namespace_ip: Ipv4Addr,
+    pub uid: u32,
+    pub gid: u32,
 }
 
 impl NetworkAllocation {
@@ -22,31 +35,63 @@ impl NetworkAllocation {
             tap_name: format!("tap{}", idx),
             host_ip,
             client_ip,
+            namespace: None,
         }
     }
 
+    // Move the tap device into its own network namespace, owned by the given user. The
+    // namespace is connected to the host with a /24 of the /16 `veth_network`.
+    pub fn with_namespace(
+        mut self,
+        name: &str,
+        uid: u32,
+        gid: u32,
+        veth_network: Ipv4Addr,
+    ) -> Self {
+        let idx = self.host_ip.octets()[2];
+        let [a, b, _, _] = veth_network.octets();
+
+        self.namespace = Some(NetworkNamespace {
+            name: name.to_string(),
+            veth_name: format!("veth{}", idx),
+            host_ip: Ipv4Addr::new(a, b, idx, 1),
+            namespace_ip: Ipv4Addr::new(a, b, idx, 2),
+            uid,
+            gid,
+        });
+        self
+    }
+
+    pub fn network_address(&self) -> Ipv4Addr {
+        let octets = self.host_ip.octets();
+        Ipv4Addr::new(octets[0], octets[1], octets[2], 0)
+    }
+
     pub fn setup(&self) -> Result<(), CommandExecutionError> {
+        if let Some(ref namespace) = self.namespace {
+            return self.setup_namespace(namespace);
+        }
+
         // Remove existing tap device
         let _ = exec(Command::new("ip").args(["link", "del", &self.tap_name]));
 
         // Create tap device
-        let _ =
-            exec(Command::new("ip").args(["tuntap", "add", "dev", &self.tap_name, "mode", "tap"]));
+        exec(Command::new("ip").args(["tuntap", "add", "dev", &self.tap_name, "mode", "tap"]))?;
 
         // Add address to tap device
-        let _ = exec(Command::new("ip").args([
+        exec(Command::new("ip").args([
             "addr",
             "add",
             &format!("{}/{}", self.host_ip, NETWORK_MASK_SHORT),
             "dev",
             &self.tap_name,
-        ]));
+        ]))?;
 
         // Bring up tap device
-        let _ = exec(Command::new("ip").args(["link", "set", "dev", &self.tap_name, "up"]));
+        exec(Command::new("ip").args(["link", "set", "dev", &self.tap_name, "up"]))?;
 
         // Set up internet access
-        let _ = exec(Command::new("iptables").args([
+        exec(Command::new("iptables").args([
             "-I",
             "FORWARD",
             "1",
@@ -56,8 +101,119 @@ impl NetworkAllocation {
             &self.interface,
             "-j",
             "ACCEPT",
-        ]));
+        ]))?;
+
+        Ok(())
+    }
+
+    fn setup_namespace(&self, namespace: &NetworkNamespace) -> Result<(), CommandExecutionError> {
+        // Remove the existing namespace, this also removes the devices in it
+        let _ = exec(Command::new("ip").args(["netns", "del", &namespace.name]));
+        let _ = exec(Command::new("ip").args(["link", "del", &namespace.veth_name]));
+
+        exec(Command::new("ip").args(["netns", "add", &namespace.name]))?;
+
+        // Connect the namespace to the host
+        exec(Command::new("ip").args([
+            "link",
+            "add",
+            &namespace.veth_name,
+            "type",
+            "veth",
+            "peer",
+            "name",
+            "veth0",
+            "netns",
+            &namespace.name,
+        ]))?;
+        exec(Command::new("ip").args([
+            "addr",
+            "add",
+            &format!("{}/{}", namespace.host_ip, NETWORK_MASK_SHORT),
+            "dev",
+            &namespace.veth_name,
+        ]))?;
+        exec(Command::new("ip").args(["link", "set", "dev", &namespace.veth_name, "up"]))?;
+
+        exec(netns_command(&namespace.name).args([
+            "ip",
+            "addr",
+            "add",
+            &format!("{}/{}", namespace.namespace_ip, NETWORK_MASK_SHORT),
+            "dev",
+            "veth0",
+        ]))?;
+        exec(netns_command(&namespace.name).args(["ip", "link", "set", "dev", "veth0", "up"]))?;
+        exec(netns_command(&namespace.name).args(["ip", "link", "set", "dev", "lo", "up"]))?;
+        exec(netns_command(&namespace.name).args([
+            "ip",
+            "route",
+            "add",
+            "default",
+            "via",
+            &namespace.host_ip.to_string(),
+        ]))?;
+
+        // Create the tap device in the namespace, owned by the jailed user
+        exec(netns_command(&namespace.name).args([
+            "ip",
+            "tuntap",
+            "add",
+            "dev",
+            &self.tap_name,
+            "mode",
+            "tap",
+            "user",
+            &namespace.uid.to_string(),
+            "group",
+            &namespace.gid.to_string(),
+        ]))?;
+        exec(netns_command(&namespace.name).args([
+            "ip",
+            "addr",
+            "add",
+            &format!("{}/{}", self.host_ip, NETWORK_MASK_SHORT),
+            "dev",
+            &self.tap_name,
+        ]))?;
+        exec(netns_command(&namespace.name).args([
+            "ip",
+            "link",
+            "set",
+            "dev",
+            &self.tap_name,
+            "up",
+        ]))?;
+        exec(netns_command(&namespace.name).args(["sysctl", "-w", "net.ipv4.ip_forward=1"]))?;
+
+        // Route the guest's network through the namespace
+        exec(Command::new("ip").args([
+            "route",
+            "replace",
+            &format!("{}/{}", self.network_address(), NETWORK_MASK_SHORT),
+            "via",
+            &namespace.namespace_ip.to_string(),
+        ]))?;
+
+        // Set up internet access
+        exec(Command::new("iptables").args([
+            "-I",
+            "FORWARD",
+            "1",
+            "-i",
+            &namespace.veth_name,
+            "-o",
+            &self.interface,
+            "-j",
+            "ACCEPT",
+        ]))?;
 
         Ok(())
     }
 }
+
+fn netns_command(namespace: &str) -> Command {
+    let mut command = Command::new("ip");
+    command.args(["netns", "exec", namespace]);
+    command
+}
@@ -0,0 +1,34 @@
+use crate::disk::{Disk, DiskFormat};
+use camino::{Utf8Path, Utf8PathBuf};
+
+// A full Firecracker snapshot of a booted template VM, shared by all instances of a role
+#[derive(Debug)]
+pub struct Snapshot {
+    pub path: Utf8PathBuf,
+    pub overlay: Disk,
+}
+
+impl Snapshot {
+    pub fn new(path: impl AsRef<Utf8Path>, overlay_size: u32) -> Self {
+        let path = path.as_ref().to_path_buf();
+
+        Self {
+            overlay: Disk::new(&path, "overlay", overlay_size, DiskFormat::Ext4),
+            path,
+        }
+    }
+
+    pub fn vmstate_path(&self) -> Utf8PathBuf {
+        self.path.join("vmstate")
+    }
+
+    pub fn memory_path(&self) -> Utf8PathBuf {
+        self.path.join("memory")
+    }
+
+    pub fn exists(&self) -> bool {
+        self.vmstate_path().exists()
+            && self.memory_path().exists()
+            && self.overlay.path_with_filename().exists()
+    }
+}
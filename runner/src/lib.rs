@@ -1,6 +1,10 @@
-use anyhow::Result;
-use std::process::Command;
-use util::exec;
+use anyhow::{anyhow, Result};
+use config::{events::GuestEvent, GUEST_CACHE_PATH};
+use log::*;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use util::{exec, fs, vsock};
 
 pub struct Runner {}
 
@@ -32,8 +36,82 @@ impl Runner {
                 .arg("--labels")
                 .arg(std::env::var("GITHUB_RUNNER_LABELS").unwrap()),
         )?;
+        report(GuestEvent::Registered);
 
-        exec(&mut Command::new("/home/runner/run.sh"))?;
+        // Follow the output of the runner, to tell the manager when a job starts and finishes
+        let mut child = Command::new("/home/runner/run.sh")
+            .stdout(Stdio::piped())
+            .spawn()?;
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                info!("{}", line);
+                if let Some(event) = parse_event(&line) {
+                    report(event);
+                }
+            }
+        }
+        let status = child.wait()?;
+
+        // The manager can't see inside every filesystem, so we tell it how full the cache is
+        if Path::new(GUEST_CACHE_PATH).exists() {
+            match fs::fs_usage(GUEST_CACHE_PATH) {
+                Ok(usage) => report(GuestEvent::CacheUsage {
+                    used_bytes: usage.used_bytes,
+                    total_bytes: usage.total_bytes,
+                }),
+                Err(e) => warn!("Could not get cache usage: {}", e),
+            }
+        }
+        report(GuestEvent::ShuttingDown);
+
+        if !status.success() {
+            return Err(anyhow!("Runner exited with status: {}", status));
+        }
         Ok(())
     }
 }
+
+// The runner logs lines like `2024-01-20 12:00:00Z: Running job: build` and
+// `2024-01-20 12:05:00Z: Job build completed with result: Succeeded`.
+fn parse_event(line: &str) -> Option<GuestEvent> {
+    if let Some((_, job)) = line.split_once("Running job: ") {
+        return Some(GuestEvent::JobStarted {
+            job: job.trim().to_string(),
+        });
+    }
+    if let Some((_, result)) = line.split_once(" completed with result: ") {
+        return Some(GuestEvent::JobFinished {
+            result: result.trim().to_string(),
+        });
+    }
+    None
+}
+
+// The manager can do without our events, so we only log when they can't be sent
+fn report(event: GuestEvent) {
+    if let Err(e) = vsock::report_event(&event) {
+        warn!("Could not report {:?} to the manager: {}", event, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        assert_eq!(
+            parse_event("2024-01-20 12:00:00Z: Running job: build"),
+            Some(GuestEvent::JobStarted {
+                job: "build".to_string()
+            })
+        );
+        assert_eq!(
+            parse_event("2024-01-20 12:05:00Z: Job build completed with result: Succeeded"),
+            Some(GuestEvent::JobFinished {
+                result: "Succeeded".to_string()
+            })
+        );
+        assert_eq!(parse_event("√ Connected to GitHub"), None);
+    }
+}
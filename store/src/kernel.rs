@@ -0,0 +1,253 @@
+use crate::{Reference, Store, StoreError};
+use camino::{Utf8Path, Utf8PathBuf};
+use log::*;
+use std::io::Read;
+use util::fs;
+
+// Kernels are tagged by version under this name, roles refer to them as e.g. `vmlinux:5.10`
+pub const KERNEL_NAME: &str = "vmlinux";
+
+const HEADER_SIZE: usize = 1024;
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 62;
+// Firecracker boots an uncompressed ELF on x86_64, and an uncompressed `Image` on aarch64
+const ARM64_IMAGE_MAGIC_OFFSET: usize = 0x38;
+const ARM64_IMAGE_MAGIC: &[u8] = b"ARM\x64";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+
+pub fn reference(version: &str) -> Result<Reference, String> {
+    format!("{}:{}", KERNEL_NAME, version).parse()
+}
+
+// Add a kernel from a file or a URL to the store, tagged with its version. It's only added
+// when it matches the given sha256, and Firecracker can boot it on this architecture.
+// Downloads need a sha256, we're about to boot every VM with what we get.
+pub fn import(
+    store: &Store,
+    source: &str,
+    sha256: Option<&str>,
+    reference: &Reference,
+) -> Result<String, StoreError> {
+    let scheme = source
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase());
+    let downloaded = if let Some(scheme) = scheme {
+        if scheme != "https" {
+            return Err(StoreError::InsecureDownload(format!(
+                "'{}' isn't an https URL",
+                source
+            )));
+        }
+        if sha256.is_none() {
+            return Err(StoreError::InsecureDownload(format!(
+                "'{}' needs a sha256 to check it against",
+                source
+            )));
+        }
+        let download_path = store
+            .path
+            .join(store.kind.dir())
+            .join(format!("download-{}", std::process::id()));
+        download(source, &download_path)?;
+        Some(download_path)
+    } else {
+        None
+    };
+    let path = downloaded
+        .clone()
+        .unwrap_or_else(|| Utf8PathBuf::from(source));
+
+    let result = verify(&path, sha256).and_then(|_| store.tag(path.as_str(), reference));
+    if let Some(download_path) = downloaded {
+        let _ = std::fs::remove_file(download_path);
+    }
+    result
+}
+
+pub fn verify(path: &Utf8Path, sha256: Option<&str>) -> Result<(), StoreError> {
+    if let Some(expected) = sha256 {
+        let actual = fs::sha256(path)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(StoreError::Checksum {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+        debug!("Kernel '{}' matches sha256: {}", path, actual);
+    }
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    std::fs::File::open(path)?
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut header)?;
+    check_header(&header, std::env::consts::ARCH).map_err(StoreError::InvalidKernel)
+}
+
+fn download(url: &str, target: &Utf8Path) -> Result<(), StoreError> {
+    info!("Downloading kernel from: {}", url);
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Kernels take longer than the default timeout of the blocking client
+    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+    let mut response = client.get(url).send()?.error_for_status()?;
+    let mut file = std::fs::File::create(target)?;
+    response.copy_to(&mut file)?;
+    Ok(())
+}
+
+fn check_header(header: &[u8], arch: &str) -> Result<(), String> {
+    if header.starts_with(GZIP_MAGIC) {
+        return Err("it's gzip compressed, decompress it first".to_string());
+    }
+    if header.get(BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + BZIMAGE_MAGIC.len())
+        == Some(BZIMAGE_MAGIC)
+    {
+        return Err(
+            "it's a compressed bzImage, extract the vmlinux with `extract-vmlinux`".to_string(),
+        );
+    }
+
+    match arch {
+        "aarch64" => {
+            if header.get(ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4)
+                == Some(ARM64_IMAGE_MAGIC)
+            {
+                Ok(())
+            } else {
+                Err("it's not an uncompressed arm64 Image".to_string())
+            }
+        }
+        _ => {
+            if !header.starts_with(ELF_MAGIC) {
+                return Err("it's not an ELF vmlinux".to_string());
+            }
+            if header.get(4) != Some(&ELF_CLASS_64) {
+                return Err("it's not a 64-bit ELF".to_string());
+            }
+            let half_word = |offset: usize| {
+                header
+                    .get(offset..offset + 2)
+                    .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+            };
+            if half_word(16) != Some(ELF_TYPE_EXEC) {
+                return Err("it's not an executable ELF".to_string());
+            }
+            if half_word(18) != Some(ELF_MACHINE_X86_64) {
+                return Err(format!("it's not built for {}", arch));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::{inner, mock_inner, MTX};
+
+    fn elf_header(class: u8, elf_type: u16, machine: u16) -> Vec<u8> {
+        let mut header = vec![0; 64];
+        header[..4].copy_from_slice(ELF_MAGIC);
+        header[4] = class;
+        header[16..18].copy_from_slice(&elf_type.to_le_bytes());
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn test_check_header() {
+        assert_eq!(
+            check_header(
+                &elf_header(ELF_CLASS_64, ELF_TYPE_EXEC, ELF_MACHINE_X86_64),
+                "x86_64"
+            ),
+            Ok(())
+        );
+        assert!(check_header(&elf_header(1, ELF_TYPE_EXEC, ELF_MACHINE_X86_64), "x86_64").is_err());
+        assert!(check_header(&elf_header(ELF_CLASS_64, 1, ELF_MACHINE_X86_64), "x86_64").is_err());
+        assert!(check_header(&elf_header(ELF_CLASS_64, ELF_TYPE_EXEC, 183), "x86_64").is_err());
+        assert!(check_header(b"\x1f\x8b\x08\x00", "x86_64")
+            .unwrap_err()
+            .contains("gzip"));
+
+        let mut bzimage = vec![0; HEADER_SIZE];
+        bzimage[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(BZIMAGE_MAGIC);
+        assert!(check_header(&bzimage, "x86_64")
+            .unwrap_err()
+            .contains("bzImage"));
+
+        let mut arm64_image = vec![0; 64];
+        arm64_image[ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4]
+            .copy_from_slice(ARM64_IMAGE_MAGIC);
+        assert_eq!(check_header(&arm64_image, "aarch64"), Ok(()));
+        assert!(check_header(&arm64_image, "x86_64").is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let _m = MTX.lock();
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "sha256sum /tmp/test_kernel_import/vmlinux-5.10.bin")
+            .returning(inner::internal_exec);
+
+        let path = Utf8PathBuf::from("/tmp/test_kernel_import");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let store = Store::kernels(&path);
+        let reference = reference("5.10").unwrap();
+
+        let kernel_path = path.join("vmlinux-5.10.bin");
+        std::fs::write(&kernel_path, b"not a kernel").unwrap();
+        assert!(matches!(
+            import(&store, kernel_path.as_str(), None, &reference),
+            Err(StoreError::InvalidKernel(_))
+        ));
+
+        let header = match std::env::consts::ARCH {
+            "aarch64" => {
+                let mut header = vec![0; 64];
+                header[ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4]
+                    .copy_from_slice(ARM64_IMAGE_MAGIC);
+                header
+            }
+            _ => elf_header(ELF_CLASS_64, ELF_TYPE_EXEC, ELF_MACHINE_X86_64),
+        };
+        std::fs::write(&kernel_path, header).unwrap();
+        assert!(matches!(
+            import(&store, kernel_path.as_str(), Some("abcd"), &reference),
+            Err(StoreError::Checksum { .. })
+        ));
+
+        let sha256 = fs::sha256(&kernel_path).unwrap();
+        assert_eq!(
+            import(&store, kernel_path.as_str(), Some(&sha256), &reference).unwrap(),
+            sha256
+        );
+        assert_eq!(
+            store.resolve_path("vmlinux:5.10".into()).unwrap(),
+            path.join(format!("kernels/blobs/{}.bin", sha256))
+        );
+
+        let url = "https://example.com/vmlinux-5.10.bin";
+        assert!(matches!(
+            import(&store, url, None, &reference),
+            Err(StoreError::InsecureDownload(_))
+        ));
+        assert!(matches!(
+            import(
+                &store,
+                "http://example.com/vmlinux-5.10.bin",
+                Some(&sha256),
+                &reference
+            ),
+            Err(StoreError::InsecureDownload(_))
+        ));
+        ctx.checkpoint();
+    }
+}
@@ -0,0 +1,428 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use config::image::ImageManifest;
+use log::*;
+use std::collections::{BTreeMap, HashSet};
+use thiserror::Error;
+use util::fs;
+
+pub mod kernel;
+pub mod reference;
+
+pub use reference::Reference;
+
+pub const DEFAULT_STORE_PATH: &str = "/var/lib/actions-runner";
+const BLOBS_DIR: &str = "blobs";
+const TAGS_DIR: &str = "tags";
+// A file per running manager, with the paths of the images and kernels its roles use
+const REFS_DIR: &str = "refs";
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("IO error: {:?}", .0)]
+    IO(#[from] std::io::Error),
+    #[error("Not found in store: {}", .0)]
+    NotFound(String),
+    #[error("Download error: {:?}", .0)]
+    Download(#[from] reqwest::Error),
+    #[error("Checksum mismatch, expected: {expected}, got: {actual}")]
+    Checksum { expected: String, actual: String },
+    #[error("Invalid kernel: {}", .0)]
+    InvalidKernel(String),
+    #[error("Refusing to download: {}", .0)]
+    InsecureDownload(String),
+}
+
+// What's kept in a store, each kind in a directory of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Image,
+    Kernel,
+}
+
+impl Kind {
+    fn dir(&self) -> &'static str {
+        match self {
+            Kind::Image => "images",
+            Kind::Kernel => "kernels",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Kind::Image => "img",
+            Kind::Kernel => "bin",
+        }
+    }
+}
+
+// An image or kernel in the store, with the tags that point to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stored {
+    pub id: String,
+    pub path: Utf8PathBuf,
+    pub tags: Vec<Reference>,
+    pub size_bytes: u64,
+    pub manifest: Option<ImageManifest>,
+}
+
+pub struct Store {
+    path: Utf8PathBuf,
+    kind: Kind,
+}
+
+impl Store {
+    pub fn new(path: impl AsRef<Utf8Path>, kind: Kind) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            kind,
+        }
+    }
+
+    pub fn images(path: impl AsRef<Utf8Path>) -> Self {
+        Self::new(path, Kind::Image)
+    }
+
+    pub fn kernels(path: impl AsRef<Utf8Path>) -> Self {
+        Self::new(path, Kind::Kernel)
+    }
+
+    fn blobs_path(&self) -> Utf8PathBuf {
+        self.path.join(self.kind.dir()).join(BLOBS_DIR)
+    }
+
+    fn tags_path(&self) -> Utf8PathBuf {
+        self.path.join(self.kind.dir()).join(TAGS_DIR)
+    }
+
+    fn refs_path(&self) -> Utf8PathBuf {
+        self.path.join(REFS_DIR)
+    }
+
+    fn blob_path(&self, id: &str) -> Utf8PathBuf {
+        self.blobs_path()
+            .join(format!("{}.{}", id, self.kind.extension()))
+    }
+
+    fn tag_path(&self, reference: &Reference) -> Utf8PathBuf {
+        self.tags_path().join(&reference.name).join(&reference.tag)
+    }
+
+    // Copy a file into the store by the hash of its contents, with the manifest of an image if
+    // it has one. Returns the id of the file.
+    pub fn import(&self, path: &Utf8Path) -> Result<String, StoreError> {
+        let id = fs::sha256(path)?;
+        let blob_path = self.blob_path(&id);
+        if blob_path.exists() {
+            debug!("'{}' is already stored as: {}", path, id);
+            return Ok(id);
+        }
+
+        // Copy next to the blob first, so a failed copy never ends up in the store
+        std::fs::create_dir_all(self.blobs_path())?;
+        let partial_path = Utf8PathBuf::from(format!("{}.partial", blob_path));
+        info!("Importing '{}' into {} as: {}", path, self.kind.dir(), id);
+        fs::clone_file(path, &partial_path, fs::CloneStrategy::Reflink)?;
+
+        let manifest_path = ImageManifest::sidecar_path(path);
+        if manifest_path.exists() {
+            std::fs::copy(&manifest_path, ImageManifest::sidecar_path(&blob_path))?;
+        }
+        std::fs::rename(&partial_path, &blob_path)?;
+
+        Ok(id)
+    }
+
+    // Point a tag at something in the store, or at a file which is imported first
+    pub fn tag(&self, source: &str, reference: &Reference) -> Result<String, StoreError> {
+        let source_path = Utf8Path::new(source);
+        let id = match source.parse::<Reference>() {
+            Ok(source) if !source_path.exists() => self.id(&source)?,
+            _ => self.import(source_path)?,
+        };
+
+        let tag_path = self.tag_path(reference);
+        if let Some(parent) = tag_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&tag_path, &id)?;
+        info!("Tagged {} as: {}", id, reference);
+
+        Ok(id)
+    }
+
+    pub fn id(&self, reference: &Reference) -> Result<String, StoreError> {
+        match std::fs::read_to_string(self.tag_path(reference)) {
+            Ok(id) => Ok(id.trim().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(StoreError::NotFound(reference.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn resolve(&self, reference: &Reference) -> Result<Utf8PathBuf, StoreError> {
+        let blob_path = self.blob_path(&self.id(reference)?);
+        if !blob_path.exists() {
+            return Err(StoreError::NotFound(reference.to_string()));
+        }
+        Ok(blob_path)
+    }
+
+    // The file a config refers to, either a path or a reference to the store
+    pub fn resolve_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf, StoreError> {
+        match Reference::from_path(path) {
+            Some(reference) => self.resolve(&reference),
+            None => Ok(path.to_path_buf()),
+        }
+    }
+
+    // Remove a tag, what it pointed to stays in the store until it's garbage collected
+    pub fn remove(&self, reference: &Reference) -> Result<(), StoreError> {
+        let tag_path = self.tag_path(reference);
+        match std::fs::remove_file(&tag_path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(StoreError::NotFound(reference.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(parent) = tag_path.parent() {
+            // Only succeeds when it was the last tag of this name
+            let _ = std::fs::remove_dir(parent);
+        }
+        info!("Removed tag: {}", reference);
+        Ok(())
+    }
+
+    fn tags(&self) -> Result<BTreeMap<Reference, String>, StoreError> {
+        let mut tags = BTreeMap::new();
+        for name_entry in read_dir(&self.tags_path())? {
+            for tag_entry in read_dir(&name_entry)? {
+                let reference = Reference {
+                    name: name_entry.file_name().unwrap_or_default().to_string(),
+                    tag: tag_entry.file_name().unwrap_or_default().to_string(),
+                };
+                let id = self.id(&reference)?;
+                tags.insert(reference, id);
+            }
+        }
+        Ok(tags)
+    }
+
+    pub fn list(&self) -> Result<Vec<Stored>, StoreError> {
+        let tags = self.tags()?;
+        let mut stored = Vec::new();
+        for path in read_dir(&self.blobs_path())? {
+            if path.extension() != Some(self.kind.extension()) {
+                continue;
+            }
+            let id = path.file_stem().unwrap_or_default().to_string();
+            stored.push(Stored {
+                tags: tags
+                    .iter()
+                    .filter(|(_, tag_id)| **tag_id == id)
+                    .map(|(reference, _)| reference.clone())
+                    .collect(),
+                size_bytes: std::fs::metadata(&path)?.len(),
+                manifest: ImageManifest::from_file(ImageManifest::sidecar_path(&path)).ok(),
+                id,
+                path,
+            });
+        }
+        Ok(stored)
+    }
+
+    // Record the images and kernels a manager uses, so they're not garbage collected while it
+    // runs. The refs are shared by every kind in the store. The first line is the start time of
+    // the manager, so the refs aren't mistaken for those of a later process with the same pid.
+    pub fn register(&self, paths: &[Utf8PathBuf]) -> Result<(), StoreError> {
+        std::fs::create_dir_all(self.refs_path())?;
+        let pid = std::process::id().to_string();
+        let mut contents = vec![start_time(&pid)?];
+        contents.extend(paths.iter().map(|path| canonical(path).to_string()));
+        std::fs::write(self.refs_path().join(pid), contents.join("\n"))?;
+        Ok(())
+    }
+
+    // The paths used by managers that are still running, refs of managers that are gone are removed
+    fn in_use(&self) -> Result<HashSet<Utf8PathBuf>, StoreError> {
+        let mut in_use = HashSet::new();
+        for path in read_dir(&self.refs_path())? {
+            let pid = path.file_name().unwrap_or_default();
+            let refs = std::fs::read_to_string(&path)?;
+            let mut lines = refs.lines();
+            let running = matches!(
+                (lines.next(), start_time(pid)),
+                (Some(recorded), Ok(current)) if recorded == current
+            );
+            if !running {
+                debug!("Removing refs of manager that's gone: {}", pid);
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            in_use.extend(lines.map(Utf8PathBuf::from));
+        }
+        Ok(in_use)
+    }
+
+    // Remove everything that isn't tagged and isn't used by a running manager.
+    // Returns the ids of what was removed.
+    pub fn gc(&self) -> Result<Vec<String>, StoreError> {
+        let in_use = self.in_use()?;
+        let mut removed = Vec::new();
+        for stored in self.list()? {
+            if !stored.tags.is_empty() || in_use.contains(&canonical(&stored.path)) {
+                continue;
+            }
+            info!("Removing from {}: {}", self.kind.dir(), stored.id);
+            std::fs::remove_file(&stored.path)?;
+            let manifest_path = ImageManifest::sidecar_path(&stored.path);
+            if manifest_path.exists() {
+                std::fs::remove_file(&manifest_path)?;
+            }
+            removed.push(stored.id);
+        }
+        Ok(removed)
+    }
+}
+
+// The start time of a process in clock ticks after boot, from `/proc/<pid>/stat`
+fn start_time(pid: &str) -> Result<String, StoreError> {
+    let stat = std::fs::read_to_string(Utf8Path::new("/proc").join(pid).join("stat"))?;
+    // The command name can contain spaces, the start time is the 20th field after it
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(19))
+        .map(str::to_string)
+        .ok_or_else(|| std::io::Error::other(format!("Invalid stat of process: {}", pid)).into())
+}
+
+// The same file can be spelled in many ways, compare the canonical path when it exists
+fn canonical(path: &Utf8Path) -> Utf8PathBuf {
+    path.canonicalize_utf8()
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The entries of a directory that might not exist yet, in order
+fn read_dir(path: &Utf8Path) -> Result<Vec<Utf8PathBuf>, StoreError> {
+    let entries = match path.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.into_path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::{inner, mock_inner, MTX};
+
+    fn store(name: &str) -> Store {
+        let path = Utf8PathBuf::from(format!("/tmp/test_store_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Store::images(path)
+    }
+
+    fn image(store: &Store, name: &str, contents: &str) -> Utf8PathBuf {
+        let path = store.path.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_tag_and_resolve() {
+        let _m = MTX.lock();
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c) == "sha256sum /tmp/test_store_tag_and_resolve/result.img"
+            })
+            .returning(inner::internal_exec);
+
+        let store = store("tag_and_resolve");
+        let image_path = image(&store, "result.img", "v1");
+        std::fs::write(ImageManifest::sidecar_path(&image_path), "{}").unwrap();
+        let latest: Reference = "your-project:latest".parse().unwrap();
+
+        let id = store.tag(image_path.as_str(), &latest).unwrap();
+        let blob_path = store.resolve(&latest).unwrap();
+        assert_eq!(blob_path, store.blob_path(&id));
+        assert_eq!(std::fs::read_to_string(&blob_path).unwrap(), "v1");
+        assert!(ImageManifest::sidecar_path(&blob_path).exists());
+
+        // Tag an image that's already in the store
+        let stable: Reference = "your-project:stable".parse().unwrap();
+        assert_eq!(store.tag("your-project:latest", &stable).unwrap(), id);
+
+        assert_eq!(
+            store.resolve_path("your-project:stable".into()).unwrap(),
+            blob_path
+        );
+        assert_eq!(
+            store.resolve_path("rootfs.img".into()).unwrap(),
+            Utf8PathBuf::from("rootfs.img")
+        );
+        assert!(matches!(
+            store.resolve_path("other:latest".into()),
+            Err(StoreError::NotFound(_))
+        ));
+
+        let images = store.list().unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].tags, vec![latest, stable]);
+        assert_eq!(images[0].size_bytes, 2);
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_gc() {
+        let _m = MTX.lock();
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c).starts_with("sha256sum /tmp/test_store_gc/"))
+            .times(2)
+            .returning(inner::internal_exec);
+
+        let store = store("gc");
+        let latest: Reference = "your-project:latest".parse().unwrap();
+        let v1 = store
+            .tag(image(&store, "v1.img", "v1").as_str(), &latest)
+            .unwrap();
+        let v1_path = store.resolve(&latest).unwrap();
+        let v2 = store
+            .tag(image(&store, "v2.img", "v2").as_str(), &latest)
+            .unwrap();
+
+        // The previous version is kept while a running manager uses it, also when it's
+        // registered by another path to the same file
+        let v1_path = store
+            .path
+            .join("images/../images/blobs")
+            .join(v1_path.file_name().unwrap());
+        store.register(&[v1_path]).unwrap();
+        std::fs::write(store.refs_path().join(u32::MAX.to_string()), "").unwrap();
+        // The refs of a process that reused the pid of a manager that's gone
+        let reused = start_time("1").unwrap() + "0";
+        std::fs::write(store.refs_path().join("1"), reused).unwrap();
+        assert_eq!(store.gc().unwrap(), Vec::<String>::new());
+        assert!(!store.refs_path().join(u32::MAX.to_string()).exists());
+        assert!(!store.refs_path().join("1").exists());
+
+        store.register(&[]).unwrap();
+        assert_eq!(store.gc().unwrap(), vec![v1]);
+
+        store.remove(&latest).unwrap();
+        assert!(matches!(
+            store.remove(&latest),
+            Err(StoreError::NotFound(_))
+        ));
+        assert_eq!(store.gc().unwrap(), vec![v2]);
+        assert!(store.list().unwrap().is_empty());
+        ctx.checkpoint();
+    }
+}
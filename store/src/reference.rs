@@ -0,0 +1,88 @@
+use camino::Utf8Path;
+use std::fmt;
+use std::str::FromStr;
+
+pub const DEFAULT_TAG: &str = "latest";
+
+// An image in the store by name and tag, e.g. `your-project:latest`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reference {
+    pub name: String,
+    pub tag: String,
+}
+
+impl Reference {
+    // A path in a config that names an image in the store instead of a file. Paths that
+    // contain a `/` or no `:` are files, e.g. `rootfs.img` or `./your-project:latest`.
+    pub fn from_path(path: &Utf8Path) -> Option<Self> {
+        match path.as_str().split_once(':') {
+            Some(_) if !path.as_str().contains('/') => path.as_str().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+fn valid_component(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with('.')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.name, self.tag)
+    }
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    // A reference without a tag is the `latest` one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, tag) = s.split_once(':').unwrap_or((s, DEFAULT_TAG));
+        if !valid_component(name) || !valid_component(tag) {
+            return Err(format!("Invalid image reference: {}", s));
+        }
+        Ok(Reference {
+            name: name.to_string(),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_from_str() {
+        assert_eq!(
+            "your-project:2024-01-20".parse(),
+            Ok(Reference {
+                name: "your-project".to_string(),
+                tag: "2024-01-20".to_string()
+            })
+        );
+        assert_eq!(
+            "your-project".parse::<Reference>().unwrap().to_string(),
+            "your-project:latest"
+        );
+        assert!("your-project:".parse::<Reference>().is_err());
+        assert!("../etc:passwd".parse::<Reference>().is_err());
+        assert!("your/project:latest".parse::<Reference>().is_err());
+    }
+
+    #[test]
+    fn test_reference_from_path() {
+        assert_eq!(
+            Reference::from_path("your-project:latest".into()),
+            Some("your-project:latest".parse().unwrap())
+        );
+        assert_eq!(Reference::from_path("rootfs.img".into()), None);
+        assert_eq!(
+            Reference::from_path("/srv/images/your-project:latest".into()),
+            None
+        );
+    }
+}
@@ -1,13 +1,159 @@
 use super::*;
 use camino::Utf8Path;
+use config::disk::DiskFormat;
+use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
+use nix::sys::statvfs::statvfs;
+use nix::unistd::{lseek, Whence};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
 use std::process::Command;
 
+const CLONE_PROBE_SOURCE: &str = ".clone-probe-source";
+const CLONE_PROBE_TARGET: &str = ".clone-probe-target";
+
+// The ext4 superblock starts 1024 bytes into the image
+const EXT4_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_MAGIC: u16 = 0xEF53;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+// Blocks are at most 64KiB, 1024 << 6
+const EXT4_MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+// FICLONE from linux/fs.h: _IOW(0x94, 9, int)
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneStrategy {
+    // Copy-on-write clone, supported on XFS and btrfs
+    Reflink,
+    // In-kernel copy of the data ranges, skipping holes
+    CopyFileRange,
+    // `cp --sparse=always`
+    Sparse,
+}
+
+impl std::fmt::Display for CloneStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CloneStrategy::Reflink => write!(f, "reflink"),
+            CloneStrategy::CopyFileRange => write!(f, "copy_file_range"),
+            CloneStrategy::Sparse => write!(f, "sparse copy"),
+        }
+    }
+}
+
+impl CloneStrategy {
+    // Find the fastest strategy that works for files in the given directory
+    pub fn detect(dir: impl AsRef<Utf8Path>) -> Self {
+        let source = dir.as_ref().join(CLONE_PROBE_SOURCE);
+        let target = dir.as_ref().join(CLONE_PROBE_TARGET);
+
+        let strategy = match std::fs::write(&source, b"probe") {
+            Ok(_) if reflink(&source, &target).is_ok() => CloneStrategy::Reflink,
+            Ok(_) if copy_ranges(&source, &target).is_ok() => CloneStrategy::CopyFileRange,
+            _ => CloneStrategy::Sparse,
+        };
+
+        let _ = std::fs::remove_file(&source);
+        let _ = std::fs::remove_file(&target);
+        strategy
+    }
+
+    fn fallback(&self) -> Option<Self> {
+        match self {
+            CloneStrategy::Reflink => Some(CloneStrategy::CopyFileRange),
+            CloneStrategy::CopyFileRange => Some(CloneStrategy::Sparse),
+            CloneStrategy::Sparse => None,
+        }
+    }
+}
+
+// Clone a file with the given strategy, falling back to slower strategies if
+// it's not supported for these files (e.g. when they're on different filesystems).
+// Returns the strategy that was used.
+pub fn clone_file(
+    from: impl AsRef<Utf8Path>,
+    to: impl AsRef<Utf8Path>,
+    strategy: CloneStrategy,
+) -> std::io::Result<CloneStrategy> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+
+    let result = match strategy {
+        CloneStrategy::Reflink => reflink(from, to),
+        CloneStrategy::CopyFileRange => copy_ranges(from, to),
+        CloneStrategy::Sparse => copy_sparse(from, to),
+    };
+
+    match (result, strategy.fallback()) {
+        (Ok(_), _) => Ok(strategy),
+        (Err(e), Some(fallback)) => {
+            log::debug!(
+                "Could not clone '{}' with {}, falling back to {}: {}",
+                from,
+                strategy,
+                fallback,
+                e
+            );
+            clone_file(from, to, fallback)
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+fn reflink(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    let source = File::open(from)?;
+    let target = File::create(to)?;
+
+    unsafe { ficlone(target.as_raw_fd(), source.as_raw_fd() as _) }?;
+    Ok(())
+}
+
+fn copy_ranges(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    let source = File::open(from)?;
+    let target = File::create(to)?;
+    let len = source.metadata()?.len() as i64;
+    target.set_len(len as u64)?;
+
+    // Only copy the ranges that contain data, so sparse files stay sparse
+    let mut offset = 0;
+    while offset < len {
+        let data_start = match lseek(source.as_raw_fd(), offset, Whence::SeekData) {
+            Ok(data_start) => data_start,
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let data_end = lseek(source.as_raw_fd(), data_start, Whence::SeekHole)?;
+
+        let mut offset_in = data_start;
+        let mut offset_out = data_start;
+        while offset_in < data_end {
+            let remaining = (data_end - offset_in) as usize;
+            let copied = copy_file_range(
+                &source,
+                Some(&mut offset_in),
+                &target,
+                Some(&mut offset_out),
+                remaining,
+            )?;
+            if copied == 0 {
+                break;
+            }
+        }
+        offset = data_end;
+    }
+
+    Ok(())
+}
+
 pub fn copy_sparse(from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> std::io::Result<()> {
     let from = from.as_ref();
     let to = to.as_ref();
 
     exec(Command::new("cp").args(["--sparse=always", from.as_str(), to.as_str()]))
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(std::io::Error::other)?;
 
     Ok(())
 }
@@ -15,17 +161,27 @@ pub fn copy_sparse(from: impl AsRef<Utf8Path>, to: impl AsRef<Utf8Path>) -> std:
 pub fn rm_rf(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
     let path = path.as_ref();
 
-    exec(Command::new("rm").args(["-rf", path.as_str()]))
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    exec(Command::new("rm").args(["-rf", path.as_str()])).map_err(std::io::Error::other)?;
 
     Ok(())
 }
 
+pub fn sha256(path: impl AsRef<Utf8Path>) -> std::io::Result<String> {
+    let path = path.as_ref();
+
+    let output =
+        exec(Command::new("sha256sum").arg(path.as_str())).map_err(std::io::Error::other)?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+        .ok_or_else(|| std::io::Error::other(format!("No checksum for: {}", path)))
+}
+
 pub fn mkdir_p(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
     let path = path.as_ref();
 
-    exec(Command::new("mkdir").args(["-p", path.as_str()]))
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    exec(Command::new("mkdir").args(["-p", path.as_str()])).map_err(std::io::Error::other)?;
 
     Ok(())
 }
@@ -33,8 +189,198 @@ pub fn mkdir_p(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
 pub fn mkfs_ext4(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
     let path = path.as_ref();
 
-    exec(Command::new("mkfs.ext4").arg(path.as_str()))
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    exec(Command::new("mkfs.ext4").arg(path.as_str())).map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+// Create an ext4 filesystem with the contents of a directory, without mounting it. Without
+// an inode count it gets the default for its size, which a lot of small files can run out of.
+pub fn mkfs_ext4_from_dir(
+    path: impl AsRef<Utf8Path>,
+    source_dir: impl AsRef<Utf8Path>,
+    inodes: Option<u64>,
+) -> std::io::Result<()> {
+    exec(&mut mkfs_ext4_from_dir_command(path, source_dir, inodes))
+        .map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+// For when `mkfs.ext4` has to run under another command, e.g. `fakeroot`
+pub fn mkfs_ext4_from_dir_command(
+    path: impl AsRef<Utf8Path>,
+    source_dir: impl AsRef<Utf8Path>,
+    inodes: Option<u64>,
+) -> Command {
+    let mut command = Command::new("mkfs.ext4");
+    if let Some(inodes) = inodes {
+        command.args(["-N", &inodes.to_string()]);
+    }
+    command.args(["-d", source_dir.as_ref().as_str(), path.as_ref().as_str()]);
+    command
+}
+
+// The version of e2fsprogs, `mkfs.ext4 -V` prints e.g. `mke2fs 1.47.0 (5-Feb-2023)`
+pub fn mke2fs_version() -> std::io::Result<(u32, u32)> {
+    let output = exec(Command::new("mkfs.ext4").arg("-V")).map_err(std::io::Error::other)?;
+
+    [output.stderr, output.stdout]
+        .iter()
+        .find_map(|output| parse_mke2fs_version(&String::from_utf8_lossy(output)))
+        .ok_or_else(|| std::io::Error::other("Could not find the version of mkfs.ext4"))
+}
+
+fn parse_mke2fs_version(output: &str) -> Option<(u32, u32)> {
+    let version = output
+        .lines()
+        .find_map(|line| line.strip_prefix("mke2fs "))?
+        .split_whitespace()
+        .next()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+pub fn mkfs(path: impl AsRef<Utf8Path>, format: DiskFormat) -> std::io::Result<()> {
+    let path = path.as_ref();
+
+    match format {
+        DiskFormat::Ext4 => mkfs_ext4(path),
+        DiskFormat::Xfs | DiskFormat::Btrfs => {
+            exec(Command::new(format!("mkfs.{}", format)).args(["-q", path.as_str()]))
+                .map_err(std::io::Error::other)?;
+            Ok(())
+        }
+    }
+}
+
+// Punch holes where an image file only has zeroes, so the host gets those blocks back
+pub fn dig_holes(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+
+    exec(Command::new("fallocate").args(["--dig-holes", path.as_str()]))
+        .map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+// Check an unmounted ext4 image and fix what's safe to fix, e.g. after the guest using it
+// crashed. This also brings the free counts in the superblock up to date, the kernel only
+// writes them back lazily.
+pub fn e2fsck(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+    run_e2fsck(path.as_ref(), &["-p"])
+}
+
+// Check an unmounted ext4 image like `e2fsck`, and punch holes in the image file where its
+// blocks are free. That only happens on a full check.
+pub fn e2fsck_discard(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+    run_e2fsck(path.as_ref(), &["-p", "-f", "-E", "discard"])
+}
+
+fn run_e2fsck(path: &Utf8Path, args: &[&str]) -> std::io::Result<()> {
+    match exec(Command::new("e2fsck").args(args).arg(path.as_str())) {
+        Ok(_) => Ok(()),
+        // It exits with 1 when it fixed something
+        Err(CommandExecutionError::CommandFailure(result)) if result.status.code() == Some(1) => {
+            Ok(())
+        }
+        Err(e) => Err(std::io::Error::other(e)),
+    }
+}
+
+// How much of a filesystem is in use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsUsage {
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl FsUsage {
+    pub fn used_pct(&self) -> u8 {
+        if self.total_bytes == 0 {
+            return 0;
+        }
+        (self.used_bytes * 100 / self.total_bytes) as u8
+    }
+}
+
+// The usage of a mounted filesystem
+pub fn fs_usage(path: impl AsRef<Utf8Path>) -> std::io::Result<FsUsage> {
+    let stat = statvfs(path.as_ref().as_std_path())?;
+    let block_size = stat.fragment_size() as u64;
+
+    Ok(FsUsage {
+        used_bytes: (stat.blocks() as u64 - stat.blocks_free() as u64) * block_size,
+        total_bytes: stat.blocks() as u64 * block_size,
+    })
+}
+
+// The usage of an unmounted ext4 image, from the block counts in its superblock. Unlike
+// the size of the (sparse) image file, this drops when files are deleted.
+pub fn ext4_usage(path: impl AsRef<Utf8Path>) -> std::io::Result<FsUsage> {
+    let mut file = File::open(path.as_ref())?;
+    let mut superblock = [0u8; EXT4_SUPERBLOCK_SIZE];
+    file.seek(SeekFrom::Start(EXT4_SUPERBLOCK_OFFSET))?;
+    file.read_exact(&mut superblock)?;
+
+    let u16_at = |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes([
+            superblock[offset],
+            superblock[offset + 1],
+            superblock[offset + 2],
+            superblock[offset + 3],
+        ]) as u64
+    };
+
+    if u16_at(0x38) != EXT4_MAGIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("'{}' is not an ext4 image", path.as_ref()),
+        ));
+    }
+
+    // A guest could have written anything here
+    let invalid = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("'{}' has an invalid {}", path.as_ref(), what),
+        )
+    };
+
+    let mut blocks = u32_at(0x04);
+    let mut free_blocks = u32_at(0x0C);
+    if u32_at(0x60) as u32 & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+        blocks |= u32_at(0x150) << 32;
+        free_blocks |= u32_at(0x158) << 32;
+    }
+    let log_block_size = u32_at(0x18) as u32;
+    if log_block_size > EXT4_MAX_LOG_BLOCK_SIZE {
+        return Err(invalid("block size"));
+    }
+    let block_size = 1024u64 << log_block_size;
+    let used_blocks = blocks
+        .checked_sub(free_blocks)
+        .ok_or_else(|| invalid("free block count"))?;
+
+    Ok(FsUsage {
+        used_bytes: used_blocks
+            .checked_mul(block_size)
+            .ok_or_else(|| invalid("block count"))?,
+        total_bytes: blocks
+            .checked_mul(block_size)
+            .ok_or_else(|| invalid("block count"))?,
+    })
+}
+
+pub fn mkfifo(path: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+
+    // Readable and writable for jailed instances, which run as a different user
+    exec(Command::new("mkfifo").args(["-m", "0666", path.as_str()]))
+        .map_err(std::io::Error::other)?;
 
     Ok(())
 }
@@ -49,37 +395,63 @@ pub fn dd(path: impl AsRef<Utf8Path>, size_in_mb: u64) -> std::io::Result<()> {
         "bs=1M",
         &format!("count={}", size_in_mb),
     ]))
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    .map_err(std::io::Error::other)?;
 
     Ok(())
 }
 
+// The space everything in a directory takes up, in bytes. It's counted in the 4K blocks of
+// our images, as even the smallest file takes up a whole block.
+pub fn dir_size(path: impl AsRef<Utf8Path>) -> std::io::Result<u64> {
+    Ok(du_summary(path, "-B4096")? * 4096)
+}
+
+// The number of inodes a directory takes up, including its own
+pub fn dir_inodes(path: impl AsRef<Utf8Path>) -> std::io::Result<u64> {
+    du_summary(path, "--inodes")
+}
+
+fn du_summary(path: impl AsRef<Utf8Path>, unit: &str) -> std::io::Result<u64> {
+    let path = path.as_ref();
+
+    let du_output = exec(Command::new("du").args(["-s", unit, path.as_str()]))
+        .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
+        .map_err(std::io::Error::other)?;
+
+    du_output
+        .split_whitespace()
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or(std::io::Error::other(format!(
+            "Could not parse size from '{:?}'",
+            du_output
+        )))
+}
+
 pub fn du(path: impl AsRef<Utf8Path>) -> std::io::Result<u64> {
     let path = path.as_ref();
 
     let du_output = exec(Command::new("du").args([&path.as_str()]))
         .map(|o| String::from_utf8_lossy(&o.stdout).to_string())
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        .map_err(std::io::Error::other)?;
 
     let size = du_output
         .split_whitespace()
         .next()
-        .ok_or(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Couldn not split '{:?}' into number and rest", du_output),
-        ))?;
+        .ok_or(std::io::Error::other(format!(
+            "Couldn not split '{:?}' into number and rest",
+            du_output
+        )))?;
 
     size.parse().map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Could not parse '{:?}' to number: {}", size, e),
-        )
+        std::io::Error::other(format!("Could not parse '{:?}' to number: {}", size, e))
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use camino::Utf8PathBuf;
     use std::os::unix::process::ExitStatusExt;
 
     #[test]
@@ -102,6 +474,57 @@ mod tests {
         ctx.checkpoint();
     }
 
+    #[test]
+    fn test_clone_file_sparse() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "cp --sparse=always /foo.txt /bar.txt")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                })
+            });
+
+        let result = clone_file("/foo.txt", "/bar.txt", CloneStrategy::Sparse);
+        assert_eq!(result.unwrap(), CloneStrategy::Sparse);
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_clone_file_copy_file_range() {
+        let dir: Utf8PathBuf = std::env::temp_dir()
+            .join("test_clone_file_copy_file_range")
+            .try_into()
+            .expect("Invalid path");
+        std::fs::create_dir_all(&dir).expect("Could not create dir");
+
+        // A file with some data, followed by a hole
+        let source = dir.join("source.img");
+        std::fs::write(&source, b"data").expect("Could not write file");
+        File::options()
+            .write(true)
+            .open(&source)
+            .and_then(|f| f.set_len(1024 * 1024))
+            .expect("Could not set length");
+
+        let result = clone_file(
+            &source,
+            dir.join("target.img"),
+            CloneStrategy::CopyFileRange,
+        );
+        assert_eq!(result.unwrap(), CloneStrategy::CopyFileRange);
+        assert_eq!(
+            std::fs::read(&source).unwrap(),
+            std::fs::read(dir.join("target.img")).unwrap()
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_rm_rf() {
         let _m = MTX.lock();
@@ -122,6 +545,29 @@ mod tests {
         ctx.checkpoint();
     }
 
+    #[test]
+    fn test_sha256() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "sha256sum /foo.txt")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: b"b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c  /foo.txt\n"
+                        .to_vec(),
+                    stderr: vec![],
+                })
+            });
+
+        assert_eq!(
+            sha256("/foo.txt").unwrap(),
+            "b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c"
+        );
+        ctx.checkpoint();
+    }
+
     #[test]
     fn test_mkdir_p() {
         let _m = MTX.lock();
@@ -161,4 +607,164 @@ mod tests {
         assert!(result.is_ok());
         ctx.checkpoint();
     }
+
+    #[test]
+    fn test_ext4_usage() {
+        let path = Utf8PathBuf::from("/tmp/test_ext4_usage.ext4");
+        let mut image = vec![0u8; 4096];
+        let superblock = EXT4_SUPERBLOCK_OFFSET as usize;
+        image[superblock + 0x04..superblock + 0x08].copy_from_slice(&1000u32.to_le_bytes());
+        image[superblock + 0x0C..superblock + 0x10].copy_from_slice(&750u32.to_le_bytes());
+        image[superblock + 0x18..superblock + 0x1C].copy_from_slice(&2u32.to_le_bytes());
+        image[superblock + 0x38..superblock + 0x3A].copy_from_slice(&EXT4_MAGIC.to_le_bytes());
+        std::fs::write(&path, &image).expect("Could not write image");
+
+        let usage = ext4_usage(&path).expect("Could not read usage");
+        assert_eq!(
+            usage,
+            FsUsage {
+                used_bytes: 250 * 4096,
+                total_bytes: 1000 * 4096
+            }
+        );
+        assert_eq!(usage.used_pct(), 25);
+
+        // More free blocks than blocks
+        image[superblock + 0x0C..superblock + 0x10].copy_from_slice(&1001u32.to_le_bytes());
+        std::fs::write(&path, &image).expect("Could not write image");
+        assert!(ext4_usage(&path).is_err());
+
+        image[superblock + 0x0C..superblock + 0x10].copy_from_slice(&750u32.to_le_bytes());
+        image[superblock + 0x18..superblock + 0x1C].copy_from_slice(&7u32.to_le_bytes());
+        std::fs::write(&path, &image).expect("Could not write image");
+        assert!(ext4_usage(&path).is_err());
+
+        std::fs::write(&path, vec![0u8; 4096]).expect("Could not write image");
+        assert!(ext4_usage(&path).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mkfs_xfs() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "mkfs.xfs -q /dev/sda1")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                })
+            });
+
+        let result = mkfs("/dev/sda1", DiskFormat::Xfs);
+        assert!(result.is_ok());
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "du -s -B4096 /rootfs")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: b"1000\t/rootfs\n".to_vec(),
+                    stderr: vec![],
+                })
+            });
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "du -s --inodes /rootfs")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: b"25000\t/rootfs\n".to_vec(),
+                    stderr: vec![],
+                })
+            });
+
+        assert_eq!(dir_size("/rootfs").unwrap(), 1000 * 4096);
+        assert_eq!(dir_inodes("/rootfs").unwrap(), 25000);
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_e2fsck() {
+        let _m = MTX.lock();
+
+        let failure = |code: i32| {
+            CommandExecutionError::CommandFailure(Box::new(CommandResult {
+                command: "e2fsck -p /cache.ext4".to_string(),
+                stdout: String::new(),
+                stderr: String::new(),
+                status: std::process::ExitStatus::from_raw(code << 8),
+            }))
+        };
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "e2fsck -p /cache.ext4")
+            .times(1)
+            .returning(move |_| Err(failure(1)));
+        assert!(e2fsck("/cache.ext4").is_ok());
+        ctx.checkpoint();
+
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "e2fsck -p /cache.ext4")
+            .times(1)
+            .returning(move |_| Err(failure(4)));
+        assert!(e2fsck("/cache.ext4").is_err());
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_e2fsck_discard() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "e2fsck -p -f -E discard /cache.ext4")
+            .times(1)
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                })
+            });
+        assert!(e2fsck_discard("/cache.ext4").is_ok());
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_mke2fs_version() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "mkfs.ext4 -V")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: b"mke2fs 1.47.0 (5-Feb-2023)\n\tUsing EXT2FS Library version 1.47.0\n"
+                        .to_vec(),
+                })
+            });
+
+        assert_eq!(mke2fs_version().unwrap(), (1, 47));
+        ctx.checkpoint();
+
+        assert_eq!(
+            parse_mke2fs_version("mke2fs 1.42.9 (28-Dec-2013)"),
+            Some((1, 42))
+        );
+        assert_eq!(parse_mke2fs_version("mkfs.ext4: invalid option"), None);
+    }
 }
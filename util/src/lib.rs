@@ -11,6 +11,7 @@ use std::time::Instant;
 pub mod fs;
 pub mod mount;
 pub mod network;
+pub mod vsock;
 
 #[derive(Debug)]
 pub struct CommandResult {
@@ -44,11 +45,7 @@ pub enum CommandExecutionError {
     CommandFailure(Box<CommandResult>),
 }
 
-#[cfg_attr(
-    any(test, automock, feature = "testing"),
-    mockall::automock,
-    allow(dead_code)
-)]
+#[cfg_attr(any(test, feature = "testing"), mockall::automock, allow(dead_code))]
 pub mod inner {
     use super::*;
 
@@ -1,5 +1,6 @@
 use super::*;
 use camino::Utf8Path;
+use config::disk::DiskFormat;
 use std::process::Command;
 
 pub fn mount_image(
@@ -24,6 +25,91 @@ pub fn mount_ext4(
     Ok(())
 }
 
+pub fn mount_disk(
+    from: impl AsRef<Utf8Path>,
+    to: impl AsRef<Utf8Path>,
+    format: DiskFormat,
+) -> Result<(), CommandExecutionError> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+
+    let _ = exec(Command::new("mount").args(["-t", format.as_str(), from.as_str(), to.as_str()]))?;
+    Ok(())
+}
+
+pub fn mount_read_only(
+    from: impl AsRef<Utf8Path>,
+    to: impl AsRef<Utf8Path>,
+) -> Result<(), CommandExecutionError> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+
+    let _ = exec(Command::new("mount").args(["-o", "ro", from.as_str(), to.as_str()]))?;
+    Ok(())
+}
+
+pub fn mount_tmpfs(to: impl AsRef<Utf8Path>) -> Result<(), CommandExecutionError> {
+    let to = to.as_ref();
+
+    let _ = exec(Command::new("mount").args(["-t", "tmpfs", "tmpfs", to.as_str()]))?;
+    Ok(())
+}
+
+pub fn mount_devtmpfs(to: impl AsRef<Utf8Path>) -> Result<(), CommandExecutionError> {
+    let to = to.as_ref();
+
+    let _ = exec(Command::new("mount").args(["-t", "devtmpfs", "devtmpfs", to.as_str()]))?;
+    Ok(())
+}
+
+pub fn mount_overlay(
+    lower: impl AsRef<Utf8Path>,
+    upper: impl AsRef<Utf8Path>,
+    work: impl AsRef<Utf8Path>,
+    to: impl AsRef<Utf8Path>,
+) -> Result<(), CommandExecutionError> {
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.as_ref(),
+        upper.as_ref(),
+        work.as_ref()
+    );
+
+    let _ = exec(Command::new("mount").args([
+        "-t",
+        "overlay",
+        "overlay",
+        "-o",
+        &options,
+        to.as_ref().as_str(),
+    ]))?;
+    Ok(())
+}
+
+pub fn mount_bind(
+    from: impl AsRef<Utf8Path>,
+    to: impl AsRef<Utf8Path>,
+    read_only: bool,
+) -> Result<(), CommandExecutionError> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+    let options = if read_only { "bind,ro" } else { "bind" };
+
+    let _ = exec(Command::new("mount").args(["-o", options, from.as_str(), to.as_str()]))?;
+    Ok(())
+}
+
+pub fn pivot_root(
+    new_root: impl AsRef<Utf8Path>,
+    put_old: impl AsRef<Utf8Path>,
+) -> Result<(), CommandExecutionError> {
+    let new_root = new_root.as_ref();
+    let put_old = put_old.as_ref();
+
+    let _ = exec(Command::new("pivot_root").args([new_root.as_str(), put_old.as_str()]))?;
+    Ok(())
+}
+
 pub fn unmount(path: impl AsRef<Utf8Path>) -> Result<(), CommandExecutionError> {
     let path = path.as_ref();
     let _ = exec(Command::new("umount").arg(path.as_str()))?;
@@ -76,6 +162,49 @@ mod tests {
         ctx.checkpoint();
     }
 
+    #[test]
+    fn test_mount_disk() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| inner::to_string(c) == "mount -t btrfs /dev/vdb /cache")
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                })
+            });
+
+        let result = mount_disk("/dev/vdb", "/cache", DiskFormat::Btrfs);
+        assert!(result.is_ok());
+        ctx.checkpoint();
+    }
+
+    #[test]
+    fn test_mount_overlay() {
+        let _m = MTX.lock();
+
+        let ctx = mock_inner::internal_exec_context();
+        ctx.expect()
+            .withf(|c| {
+                inner::to_string(c)
+                    == "mount -t overlay overlay -o lowerdir=/,upperdir=/o/upper,workdir=/o/work /mnt"
+            })
+            .returning(|_| {
+                Ok(std::process::Output {
+                    status: std::process::ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                })
+            });
+
+        let result = mount_overlay("/", "/o/upper", "/o/work", "/mnt");
+        assert!(result.is_ok());
+        ctx.checkpoint();
+    }
+
     #[test]
     fn test_unmount() {
         let _m = MTX.lock();
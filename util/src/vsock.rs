@@ -0,0 +1,25 @@
+use config::{events::GuestEvent, GUEST_EVENTS_PORT, VSOCK_HOST_CID};
+use nix::sys::socket::{
+    connect as socket_connect, socket, AddressFamily, SockFlag, SockType, VsockAddr,
+};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+
+pub fn connect(cid: u32, port: u32) -> std::io::Result<File> {
+    let fd = socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    socket_connect(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+    Ok(File::from(fd))
+}
+
+// Report an event to the manager, every event is sent over its own connection
+pub fn report_event(event: &GuestEvent) -> std::io::Result<()> {
+    let mut stream = connect(VSOCK_HOST_CID, GUEST_EVENTS_PORT)?;
+    writeln!(stream, "{}", serde_json::to_string(event)?)?;
+    Ok(())
+}